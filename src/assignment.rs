@@ -0,0 +1,41 @@
+use crate::elev_controller::{Order, TravelDirection};
+use crate::peers::Heartbeat;
+
+/// How hall calls are divided between the elevators.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AssignmentMode {
+    /// Every elevator waits a cost dependent delay and takes the order if nobody else has.
+    TimerRace,
+    /// Every elevator computes the same assignment from the shared heartbeat state and
+    /// the winner takes the order at once. The timer race is kept as a fallback
+    /// in case the winner never takes it.
+    Shared,
+}
+
+/// Picks the elevator which should serve a hall call.
+/// Every elevator holding the same heartbeats picks the same elevator, ties go to the lowest id.
+pub fn assign(order: &Order, candidates: &[&Heartbeat], floors: u8) -> Option<u32> {
+    candidates.iter()
+        .min_by_key(|heartbeat| (cost(heartbeat, order, floors), heartbeat.elevator_id))
+        .map(|heartbeat| heartbeat.elevator_id)
+}
+
+/// Rough number of floors the car travels before it can serve the order,
+/// with every queued order counted as a stop costing two floors of travel.
+fn cost(heartbeat: &Heartbeat, order: &Order, floors: u8) -> u64 {
+    let floor = if heartbeat.floor < 0 { 0 } else { heartbeat.floor };
+    let distance = (order.floor as isize - floor).unsigned_abs() as u64;
+    let moving_away = match heartbeat.direction {
+        TravelDirection::Up => (order.floor as isize) < floor,
+        TravelDirection::Down => (order.floor as isize) > floor,
+        TravelDirection::Stop => false,
+    };
+    let mut cost = distance + heartbeat.queue_length as u64 * 2;
+    if moving_away {
+        cost += 2 * floors as u64;
+    }
+    if heartbeat.door_open {
+        cost += 1;
+    }
+    cost
+}
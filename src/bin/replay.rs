@@ -0,0 +1,213 @@
+use elevator_driver::elev_driver::N_FLOORS;
+use std::collections::BTreeMap;
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use elevator_project::building::Building;
+use elevator_project::clock::{Clock, SharedClock, VirtualClock};
+use elevator_project::elev_controller::{ButtonEvent, ButtonType, Order, RequestType};
+use elevator_project::elev_simulator::{SimElevator, DEFAULT_TRAVEL_TIME};
+use elevator_project::event_log::{Event, EventKind, EventLog};
+use elevator_project::network::MessageTransmitter;
+use elevator_project::order_state::same_order;
+use elevator_project::protocol::Message;
+use elevator_project::task_manager::TaskManager;
+
+fn help() -> String {
+    format!("Usage: replay (event log)... [--floor (n) --button (cab|up|down) [--origin (id)]] [--floors (n)]
+
+Replays the button events an elevator received, as recorded in its event log, through a task manager
+and shows how one order went through its states, both as recorded and as replayed.
+The replayed elevator takes and completes orders itself, so only its own requests are replayed
+together with everything received from other elevators.
+Give rotated logs oldest first, like replay elevator_7.events.1 elevator_7.events.
+Without --floor and --button the orders found in the log are listed.
+
+  --floor (n)        Floor of the order to follow
+  --button (name)    cab, up or down
+  --origin (id)      Elevator the cab call was made in [default: the elevator of the log]
+  --floors (n)       Number of floors of the replayed building [default: {floors}]
+
+Heartbeats are not recorded, so the replay has no peers and assigns hall calls by timer race,
+and the replayed car starts at the bottom floor.", floors = N_FLOORS)
+}
+
+/// Virtual time between two runs of the state machine, the same as in the simulation harness.
+const TICK: Duration = Duration::from_millis(10);
+/// How long the replay goes on after the last recorded event.
+const TAIL: Duration = Duration::from_secs(30);
+
+/// Transmits nothing, the replayed elevator has nobody to talk to.
+struct NullTransmitter;
+
+impl MessageTransmitter for NullTransmitter {
+    fn transmit(&self, _message: &Message) -> io::Result<u64> {
+        Ok(0)
+    }
+}
+
+struct Options {
+    logs: Vec<PathBuf>,
+    floor: Option<u8>,
+    button: Option<ButtonType>,
+    origin: Option<u32>,
+    floors: u8,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options{logs: vec![], floor: None, button: None, origin: None, floors: N_FLOORS};
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--floor" | "--button" | "--origin" | "--floors" => {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                let invalid = |_| format!("invalid value {:?} for {}", value, arg);
+                match arg.as_str() {
+                    "--floor" => options.floor = Some(value.parse().map_err(invalid)?),
+                    "--origin" => options.origin = Some(value.parse().map_err(invalid)?),
+                    "--floors" => options.floors = value.parse().map_err(invalid)?,
+                    _ => options.button = Some(match value.as_str() {
+                        "cab" => ButtonType::CabCall,
+                        "up" => ButtonType::HallUpCall,
+                        "down" => ButtonType::HallDownCall,
+                        _ => return Err(format!("invalid value {:?} for --button: expected cab, up or down", value)),
+                    }),
+                }
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown argument {:?}", arg)),
+            _ => options.logs.push(PathBuf::from(arg)),
+        }
+    }
+    if options.logs.is_empty() {
+        return Err("no event log given".to_string());
+    }
+    Building{floors: options.floors, ..Building::default()}.validate().map_err(|e| format!("invalid value {} for --floors: {}", options.floors, e))?;
+    Ok(options)
+}
+
+fn seconds_since(start_ms: u64, time_ms: u64) -> f64 {
+    time_ms.saturating_sub(start_ms) as f64 / 1000.0
+}
+
+/// Lists every order requested in the log and how often it was requested.
+fn list_orders(events: &[Event]) {
+    let mut requests: BTreeMap<(u8, String, Option<u32>), usize> = BTreeMap::new();
+    for event in events {
+        if let EventKind::Sent(ButtonEvent{request: RequestType::Request, order, origin, ..}) | EventKind::Received(ButtonEvent{request: RequestType::Request, order, origin, ..}) = &event.kind {
+            let origin = if order.order_type == ButtonType::CabCall { Some(*origin) } else { None };
+            *requests.entry((order.floor, format!("{:?}", order.order_type), origin)).or_insert(0) += 1;
+        }
+    }
+    for ((floor, button, origin), count) in requests {
+        match origin {
+            Some(origin) => println!("floor {} {} from elevator {}, requested {} times", floor, button, origin, count),
+            None => println!("floor {} {}, requested {} times", floor, button, count),
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", help());
+        return;
+    }
+    let options = match parse_options(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("[replay] {}, see --help", e);
+            std::process::exit(2);
+        }
+    };
+    let mut events: Vec<Event> = vec![];
+    for path in &options.logs {
+        match EventLog::read(path) {
+            Ok(read) => events.extend(read),
+            Err(e) => {
+                println!("[replay] {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    events.sort_by_key(|event| event.time_ms);
+    let (start_ms, end_ms, elevator_id) = match (events.first(), events.last()) {
+        (Some(first), Some(last)) => (first.time_ms, last.time_ms, first.elevator_id),
+        _ => {
+            println!("[replay] the event log is empty");
+            std::process::exit(1);
+        }
+    };
+    let wanted = match (options.floor, options.button.clone()) {
+        (Some(floor), Some(button)) => Order{floor, order_type: button},
+        (None, None) => {
+            list_orders(&events);
+            return;
+        }
+        _ => {
+            println!("[replay] --floor and --button go together, see --help");
+            std::process::exit(2);
+        }
+    };
+    let wanted_origin = options.origin.unwrap_or(elevator_id);
+
+    println!("Recorded by elevator {}:", elevator_id);
+    for event in &events {
+        match &event.kind {
+            EventKind::Sent(button_event) | EventKind::Received(button_event) if same_order(&button_event.order, button_event.origin, &wanted, wanted_origin) => {
+                let direction = if let EventKind::Sent(_) = event.kind { "sent" } else { "received" };
+                println!("  {:>9.3}s {} {:?} of press {} from elevator {}", seconds_since(start_ms, event.time_ms), direction, button_event.request, button_event.id, button_event.origin);
+            }
+            EventKind::Transition{order, origin, id, from, to} if same_order(order, *origin, &wanted, wanted_origin) => {
+                println!("  {:>9.3}s {:?} -> {:?} serving press {}", seconds_since(start_ms, event.time_ms), from, to, id);
+            }
+            _ => {}
+        }
+    }
+
+    let clock = VirtualClock::new(SystemTime::UNIX_EPOCH + Duration::from_millis(start_ms));
+    let shared_clock: SharedClock = Arc::new(clock.clone());
+    let (event_sender, event_reciver) = channel::<Event>();
+    let elevator = SimElevator::new(options.floors, DEFAULT_TRAVEL_TIME, shared_clock.clone());
+    let mut manager = match TaskManager::new(elevator_id, Box::new(NullTransmitter), elevator, shared_clock.clone(), None) {
+        Ok(manager) => manager,
+        Err(e) => {
+            println!("[replay] Failed to start the task manager: {}", e);
+            std::process::exit(1);
+        }
+    };
+    manager.set_building(Building{floors: options.floors, ..Building::default()});
+    manager.set_event_sink(Box::new(event_sender));
+
+    println!("Replayed:");
+    // Cab calls of other elevators sent again by this one are sent again by the replay too
+    let received = events.iter().filter_map(|event| match &event.kind {
+        EventKind::Sent(button_event @ ButtonEvent{request: RequestType::Request, ..}) if button_event.origin == elevator_id => Some((event.time_ms, button_event.clone())),
+        EventKind::Received(button_event) => Some((event.time_ms, button_event.clone())),
+        _ => None,
+    });
+    let mut received = received.peekable();
+    let end = SystemTime::UNIX_EPOCH + Duration::from_millis(end_ms) + TAIL;
+    while clock.now() < end {
+        let now_ms = clock.now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+        while let Some((_, button_event)) = received.next_if(|(time_ms, _)| *time_ms <= now_ms) {
+            manager.handle_message(Message::Button(button_event));
+        }
+        manager.deliver_local_events();
+        if let Err(e) = manager.run_state_machine() {
+            println!("[replay] Replay stopped: {}", e);
+            break;
+        }
+        while let Ok(event) = event_reciver.try_recv() {
+            if let EventKind::Transition{order, origin, id, from, to} = &event.kind {
+                if same_order(order, *origin, &wanted, wanted_origin) {
+                    println!("  {:>9.3}s {:?} -> {:?} serving press {}", seconds_since(start_ms, event.time_ms), from, to, id);
+                }
+            }
+        }
+        clock.advance(TICK);
+    }
+}
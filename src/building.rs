@@ -0,0 +1,60 @@
+use elevator_driver::elev_driver;
+use serde::*;
+use std::collections::BTreeSet;
+
+use crate::elev_controller::{ButtonType, Order};
+
+/// The floors of the building and which hall buttons they have.
+/// Every elevator on the network must use the same description, peers with another one are refused.
+/// It is sent in heartbeats, so unknown fields are accepted here to let later versions add some,
+/// the configuration file is checked for them by `config::Config`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Building {
+    pub floors: u8,
+    /// Floors without an up button, the top floor never has one. A set, so the order they are listed in does not matter.
+    pub no_up_button: BTreeSet<u8>,
+    /// Floors without a down button, the bottom floor never has one.
+    pub no_down_button: BTreeSet<u8>,
+}
+
+impl Default for Building {
+    fn default() -> Self {
+        Building{floors: elev_driver::N_FLOORS, no_up_button: BTreeSet::new(), no_down_button: BTreeSet::new()}
+    }
+}
+
+impl Building {
+    pub fn top_floor(&self) -> u8 {
+        self.floors - 1
+    }
+
+    pub fn has_up_button(&self, floor: u8) -> bool {
+        floor < self.top_floor() && !self.no_up_button.contains(&floor)
+    }
+
+    pub fn has_down_button(&self, floor: u8) -> bool {
+        floor > 0 && floor < self.floors && !self.no_down_button.contains(&floor)
+    }
+
+    /// Whether the button of the order exists in this building.
+    pub fn has_button_for(&self, order: &Order) -> bool {
+        match order.order_type {
+            ButtonType::CabCall => order.floor < self.floors,
+            ButtonType::HallUpCall => self.has_up_button(order.floor),
+            ButtonType::HallDownCall => self.has_down_button(order.floor),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.floors < 2 {
+            return Err(format!("a building needs at least 2 floors, got {}", self.floors));
+        }
+        for floor in self.no_up_button.iter().chain(self.no_down_button.iter()) {
+            if *floor >= self.floors {
+                return Err(format!("floor {} is not in a building with {} floors", floor, self.floors));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::cost_function::CostFunctionKind;
+use crate::logger::{LogFilter, LogFormat};
+
+pub const HELP: &str = "Usage: elevator-project [options]
+       elevator-project --harness (packet-loss|network-block) [--seed (n)] [options]
+
+Options, most of which can also be given as environment variables:
+  --id (n)                 Id of this elevator, unique on the network                  ELEVATOR_ID
+                           [default: last part of the local IP address]
+  --port (n)               UDP broadcast port shared by the elevators                  ELEVATOR_PORT
+                           [default: network.udp_broadcast_port in the configuration]
+  --hw-addr (ip[:port])    Address of the elevator hardware server                     ELEVATOR_HW_ADDR
+                           [default: hardware.ip and hardware.port in the configuration]
+  --floors (n)             Number of floors in the building                            ELEVATOR_FLOORS
+  --config (path)          TOML configuration file [default: elevator.toml if present] ELEVATOR_CONFIG
+  --log-level (filter)     off, error, warn, info, debug or trace, optionally per      ELEVATOR_LOG_LEVEL
+                           module like info,task_manager=debug [default: info]
+  --log-format (format)    text or json [default: text]                                ELEVATOR_LOG_FORMAT
+  --sim                    Run against the built-in simulated car                      ELEVATOR_SIM=1
+  --shared-assignment      Assign hall calls from the state shared in heartbeats
+  --fifo                   Serve orders in the order they were taken
+  --cost-function (name)   dong-zafar, nearest-car, time-to-serve or energy-saving
+  --harness (scenario)     Run a simulation scenario and report the order latencies
+  --seed (n)               Seed of the harness scenario [default: 0]
+  -h, --help               Show this text";
+
+/// Address of the elevator hardware server, the port is optional.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HardwareAddress {
+    pub ip: String,
+    pub port: Option<u16>,
+}
+
+impl FromStr for HardwareAddress {
+    type Err = String;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        // An address with more than one colon is a bare IPv6 address without port
+        match address.rsplit_once(':') {
+            Some((ip, port)) if !ip.contains(':') => {
+                if ip.is_empty() {
+                    return Err("the ip address is missing".to_string());
+                }
+                let port = port.parse::<u16>().map_err(|_| format!("invalid port {:?}", port))?;
+                Ok(HardwareAddress{ip: ip.to_string(), port: Some(port)})
+            }
+            _ if address.is_empty() => Err("the address is empty".to_string()),
+            _ => Ok(HardwareAddress{ip: address.to_string(), port: None}),
+        }
+    }
+}
+
+/// Everything given on the command line or in the environment.
+/// Options left out are `None`, their defaults come from the configuration file.
+#[derive(Debug, Clone)]
+pub struct Cli {
+    pub id: Option<u32>,
+    pub port: Option<u16>,
+    pub hw_addr: Option<HardwareAddress>,
+    pub floors: Option<u8>,
+    pub config: Option<PathBuf>,
+    pub log_level: LogFilter,
+    pub log_format: LogFormat,
+    pub sim: bool,
+    pub shared_assignment: bool,
+    pub fifo: bool,
+    pub cost_function: Option<CostFunctionKind>,
+    pub harness: Option<String>,
+    pub seed: u64,
+    pub help: bool,
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    UnknownOption(String),
+    MissingValue(String),
+    /// The option or environment variable, the value given and what is wrong with it.
+    InvalidValue(String, String, String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::UnknownOption(option) => write!(f, "unknown argument {:?}, see --help", option),
+            CliError::MissingValue(option) => write!(f, "{} needs a value, see --help", option),
+            CliError::InvalidValue(source, value, reason) => write!(f, "invalid value {:?} for {}: {}", value, source, reason),
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(source: &str, value: &str) -> Result<T, CliError> where T::Err: fmt::Display {
+    value.parse::<T>().map_err(|e| CliError::InvalidValue(source.to_string(), value.to_string(), e.to_string()))
+}
+
+fn parse_flag(source: &str, value: &str) -> Result<bool, CliError> {
+    match value {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" | "" => Ok(false),
+        _ => Err(CliError::InvalidValue(source.to_string(), value.to_string(), "expected 1 or 0".to_string())),
+    }
+}
+
+impl Cli {
+    /// Parses the arguments, program name excluded. Options not given on the command line
+    /// are looked up with `env`, normally `std::env::var`.
+    pub fn parse<F: Fn(&str) -> Option<String>>(args: &[String], env: F) -> Result<Cli, CliError> {
+        let mut cli = Cli {
+            id: None,
+            port: None,
+            hw_addr: None,
+            floors: None,
+            config: None,
+            log_level: LogFilter::new(log::LevelFilter::Info),
+            log_format: LogFormat::Text,
+            sim: false,
+            shared_assignment: false,
+            fifo: false,
+            cost_function: None,
+            harness: None,
+            seed: 0,
+            help: false,
+        };
+        if let Some(value) = env("ELEVATOR_ID") {
+            cli.id = Some(parse_value("ELEVATOR_ID", &value)?);
+        }
+        if let Some(value) = env("ELEVATOR_PORT") {
+            cli.port = Some(parse_value("ELEVATOR_PORT", &value)?);
+        }
+        if let Some(value) = env("ELEVATOR_HW_ADDR") {
+            cli.hw_addr = Some(parse_value("ELEVATOR_HW_ADDR", &value)?);
+        }
+        if let Some(value) = env("ELEVATOR_FLOORS") {
+            cli.floors = Some(parse_value("ELEVATOR_FLOORS", &value)?);
+        }
+        if let Some(value) = env("ELEVATOR_CONFIG") {
+            cli.config = Some(PathBuf::from(value));
+        }
+        if let Some(value) = env("ELEVATOR_LOG_LEVEL") {
+            cli.log_level = parse_value("ELEVATOR_LOG_LEVEL", &value)?;
+        }
+        if let Some(value) = env("ELEVATOR_LOG_FORMAT") {
+            cli.log_format = parse_value("ELEVATOR_LOG_FORMAT", &value)?;
+        }
+        if let Some(value) = env("ELEVATOR_SIM") {
+            cli.sim = parse_flag("ELEVATOR_SIM", &value)?;
+        }
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            // Both `--option value` and `--option=value` are accepted
+            let (option, inline_value) = match arg.find('=') {
+                Some(index) if arg.starts_with("--") => (&arg[..index], Some(arg[index + 1..].to_string())),
+                _ => (arg.as_str(), None),
+            };
+            match option {
+                "-h" | "--help" => cli.help = true,
+                "--sim" => cli.sim = true,
+                "--shared-assignment" => cli.shared_assignment = true,
+                "--fifo" => cli.fifo = true,
+                "--id" | "--port" | "--hw-addr" | "--floors" | "--config" | "--log-level" | "--log-format" | "--cost-function" | "--harness" | "--seed" => {
+                    let value = match inline_value {
+                        Some(value) => value,
+                        None => args.next().cloned().ok_or_else(|| CliError::MissingValue(option.to_string()))?,
+                    };
+                    match option {
+                        "--id" => cli.id = Some(parse_value(option, &value)?),
+                        "--port" => cli.port = Some(parse_value(option, &value)?),
+                        "--hw-addr" => cli.hw_addr = Some(parse_value(option, &value)?),
+                        "--floors" => cli.floors = Some(parse_value(option, &value)?),
+                        "--config" => cli.config = Some(PathBuf::from(value)),
+                        "--log-level" => cli.log_level = parse_value(option, &value)?,
+                        "--log-format" => cli.log_format = parse_value(option, &value)?,
+                        "--cost-function" => cli.cost_function = Some(parse_value(option, &value)?),
+                        "--harness" => cli.harness = Some(value),
+                        _ => cli.seed = parse_value(option, &value)?,
+                    }
+                }
+                _ => return Err(CliError::UnknownOption(arg.to_string())),
+            }
+        }
+        Ok(cli)
+    }
+}
@@ -0,0 +1,58 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::SystemTimeError;
+
+/// Source of the current time for door timing, cost function delays and watchdogs.
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+
+    /// Time passed since the timer `since` was started.
+    /// If the clock has stepped backwards the timer is restarted from the current time
+    /// and the error returned, so the caller can skip this tick instead of waiting for the clock to catch up.
+    fn elapsed(&self, since: &mut SystemTime) -> Result<Duration, SystemTimeError> {
+        let now = self.now();
+        match now.duration_since(*since) {
+            Ok(elapsed) => Ok(elapsed),
+            Err(e) => {
+                *since = now;
+                Err(e)
+            }
+        }
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock + Send + Sync>;
+
+/// Wall clock time, used when running against real or simulated hardware.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock which only moves when it is advanced, used for reproducible simulations.
+/// Clones share the same time.
+#[derive(Clone)]
+pub struct VirtualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl VirtualClock {
+    pub fn new(start: SystemTime) -> Self {
+        VirtualClock{now: Arc::new(Mutex::new(start))}
+    }
+
+    pub fn advance(&self, step: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += step;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
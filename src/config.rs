@@ -0,0 +1,345 @@
+use elevator_driver::elev_driver;
+use serde::*;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::building::Building;
+use crate::cost_function::CostFunctionKind;
+use crate::elev_controller;
+
+/// Settings read from the TOML configuration file at startup.
+/// Every key is optional, missing keys keep their default. Times are in milliseconds.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(deserialize_with = "strict_building")]
+    pub building: Building,
+    pub elevator: ElevatorConfig,
+    pub orders: OrderConfig,
+    pub network: NetworkConfig,
+    pub hardware: HardwareConfig,
+    pub cost: CostConfig,
+    pub event_log: EventLogConfig,
+    pub metrics: MetricsConfig,
+}
+
+/// `[building]` as written in the configuration file. `Building` accepts unknown fields since it is
+/// also sent in heartbeats, a misspelt key in the file is still an error.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BuildingSection {
+    floors: u8,
+    no_up_button: BTreeSet<u8>,
+    no_down_button: BTreeSet<u8>,
+}
+
+impl Default for BuildingSection {
+    fn default() -> Self {
+        let building = Building::default();
+        BuildingSection{floors: building.floors, no_up_button: building.no_up_button, no_down_button: building.no_down_button}
+    }
+}
+
+fn strict_building<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Building, D::Error> {
+    let section = BuildingSection::deserialize(deserializer)?;
+    Ok(Building{floors: section.floors, no_up_button: section.no_up_button, no_down_button: section.no_down_button})
+}
+
+/// Door and motor timing of the local car.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ElevatorConfig {
+    pub door_open_time_ms: u64,
+    /// A door obstructed for longer than this takes the elevator out of service.
+    pub door_obstruction_fault_ms: u64,
+    /// Longest time the motor may run without the car reaching a new floor.
+    pub travel_timeout_ms: u64,
+    pub stuck_probe_interval_ms: u64,
+    pub stuck_probe_time_ms: u64,
+}
+
+/// Timing of the order state machine in `TaskManager`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrderConfig {
+    /// How often cab calls of other elevators are broadcast again until they are served.
+    pub cab_rebroadcast_interval_ms: u64,
+    /// Time per floor an elevator gets to serve a hall call it has taken before others take over.
+    pub complete_watchdog_per_floor_ms: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub udp_broadcast_port: u16,
+    /// Number of times every button event is sent.
+    pub retransmit_count: u32,
+    pub retransmit_interval_ms: u64,
+    /// Time to wait for a `Taken` or `Complete` event to be acknowledged before sending it again.
+    pub ack_timeout_ms: u64,
+    /// How often the digest of the task list is broadcast.
+    pub digest_interval_ms: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HardwareConfig {
+    pub ip: String,
+    pub port: u16,
+}
+
+/// The cost function used and the constants of every built in cost function.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CostConfig {
+    pub function: CostFunctionKind,
+    /// Delay before taking a cab call, which only this elevator can serve.
+    pub cab_call_delay_ms: u64,
+    /// Added per elevator id so two elevators never get exactly the same delay.
+    pub id_delay_ms: u64,
+    /// Delay every hall call starts from in the nearest-car, time-to-serve and energy-saving functions.
+    pub base_delay_ms: u64,
+    /// Delay per floor of distance in the nearest-car and energy-saving functions.
+    pub floor_delay_ms: u64,
+    /// Assumed travel time per floor and time per stop in the time-to-serve function.
+    pub floor_travel_ms: u64,
+    pub stop_ms: u64,
+    /// Added in the energy-saving function when an idle car would have to start.
+    pub idle_start_ms: u64,
+    /// Dong-Zafar delay when the car has orders, before the score and queue delays are added.
+    pub dz_busy_base_ms: u64,
+    /// Dong-Zafar delay divided by the direction score.
+    pub dz_score_ms: u64,
+    /// Dong-Zafar delay per order already in the queue.
+    pub dz_queued_order_ms: u64,
+    /// Dong-Zafar delay when the car is idle, before the distance delay is added.
+    pub dz_idle_base_ms: u64,
+    /// Dong-Zafar delay per floor of distance when the car is idle.
+    pub dz_idle_floor_ms: u64,
+}
+
+/// The order lifecycle event log, `elevator_(id).events` in the working directory.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventLogConfig {
+    pub enabled: bool,
+    /// Size at which the log is moved aside and a new one started.
+    pub max_file_bytes: u64,
+    /// Number of old logs kept.
+    pub rotated_files: u32,
+}
+
+/// The HTTP endpoint serving Prometheus metrics at `/metrics`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub address: String,
+}
+
+impl Default for ElevatorConfig {
+    fn default() -> Self {
+        ElevatorConfig {
+            door_open_time_ms: 3000,
+            door_obstruction_fault_ms: 20000,
+            travel_timeout_ms: 8000,
+            stuck_probe_interval_ms: 10000,
+            stuck_probe_time_ms: 2000,
+        }
+    }
+}
+
+impl Default for OrderConfig {
+    fn default() -> Self {
+        OrderConfig {
+            cab_rebroadcast_interval_ms: 10000,
+            complete_watchdog_per_floor_ms: 3000,
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            udp_broadcast_port: elev_controller::BCAST_PORT,
+            retransmit_count: 3,
+            retransmit_interval_ms: 50,
+            ack_timeout_ms: 100,
+            digest_interval_ms: 1000,
+        }
+    }
+}
+
+impl Default for HardwareConfig {
+    fn default() -> Self {
+        HardwareConfig {
+            ip: elev_driver::DEFAULT_IP_ADDRESS.to_string(),
+            port: elev_driver::DEFAULT_PORT,
+        }
+    }
+}
+
+impl Default for CostConfig {
+    fn default() -> Self {
+        CostConfig {
+            function: CostFunctionKind::DongZafar,
+            cab_call_delay_ms: 20,
+            id_delay_ms: 150,
+            base_delay_ms: 500,
+            floor_delay_ms: 1000,
+            floor_travel_ms: 2000,
+            stop_ms: 3000,
+            idle_start_ms: 1500,
+            dz_busy_base_ms: 2000,
+            dz_score_ms: 5000,
+            dz_queued_order_ms: 2500,
+            dz_idle_base_ms: 1000,
+            dz_idle_floor_ms: 500,
+        }
+    }
+}
+
+impl Default for EventLogConfig {
+    fn default() -> Self {
+        EventLogConfig {
+            enabled: true,
+            max_file_bytes: 10_000_000,
+            rotated_files: 3,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: true,
+            address: "127.0.0.1:9464".to_string(),
+        }
+    }
+}
+
+impl ElevatorConfig {
+    pub fn door_open_time(&self) -> Duration {
+        Duration::from_millis(self.door_open_time_ms)
+    }
+
+    pub fn door_obstruction_fault(&self) -> Duration {
+        Duration::from_millis(self.door_obstruction_fault_ms)
+    }
+
+    pub fn travel_timeout(&self) -> Duration {
+        Duration::from_millis(self.travel_timeout_ms)
+    }
+
+    pub fn stuck_probe_interval(&self) -> Duration {
+        Duration::from_millis(self.stuck_probe_interval_ms)
+    }
+
+    pub fn stuck_probe_time(&self) -> Duration {
+        Duration::from_millis(self.stuck_probe_time_ms)
+    }
+}
+
+impl OrderConfig {
+    pub fn cab_rebroadcast_interval(&self) -> Duration {
+        Duration::from_millis(self.cab_rebroadcast_interval_ms)
+    }
+
+    pub fn complete_watchdog(&self, floors: u8) -> Duration {
+        Duration::from_millis(self.complete_watchdog_per_floor_ms * floors as u64)
+    }
+}
+
+impl NetworkConfig {
+    pub fn retransmit_interval(&self) -> Duration {
+        Duration::from_millis(self.retransmit_interval_ms)
+    }
+
+    pub fn ack_timeout(&self) -> Duration {
+        Duration::from_millis(self.ack_timeout_ms)
+    }
+
+    pub fn digest_interval(&self) -> Duration {
+        Duration::from_millis(self.digest_interval_ms)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "unable to read configuration: {}", e),
+            ConfigError::Parse(e) => write!(f, "malformed configuration: {}", e),
+            ConfigError::Invalid(e) => write!(f, "invalid configuration: {}", e),
+        }
+    }
+}
+
+impl Config {
+    /// Reads and validates a configuration file.
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        Config::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(text).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings the elevators can not work with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::Invalid(message.to_string()));
+        if let Err(e) = self.building.validate() {
+            return invalid(&format!("building: {}", e));
+        }
+        if self.elevator.door_open_time_ms == 0 {
+            return invalid("elevator.door_open_time_ms must be above 0");
+        }
+        if self.elevator.door_obstruction_fault_ms <= self.elevator.door_open_time_ms {
+            return invalid("elevator.door_obstruction_fault_ms must be longer than elevator.door_open_time_ms");
+        }
+        if self.elevator.travel_timeout_ms == 0 {
+            return invalid("elevator.travel_timeout_ms must be above 0");
+        }
+        if self.elevator.stuck_probe_time_ms == 0 || self.elevator.stuck_probe_time_ms >= self.elevator.stuck_probe_interval_ms {
+            return invalid("elevator.stuck_probe_time_ms must be above 0 and shorter than elevator.stuck_probe_interval_ms");
+        }
+        if self.orders.cab_rebroadcast_interval_ms == 0 {
+            return invalid("orders.cab_rebroadcast_interval_ms must be above 0");
+        }
+        if self.orders.complete_watchdog_per_floor_ms == 0 {
+            return invalid("orders.complete_watchdog_per_floor_ms must be above 0");
+        }
+        if self.network.retransmit_count == 0 {
+            return invalid("network.retransmit_count must be at least 1");
+        }
+        if self.network.retransmit_count > 1 && self.network.retransmit_interval_ms == 0 {
+            return invalid("network.retransmit_interval_ms must be above 0 when retransmitting");
+        }
+        if self.network.ack_timeout_ms == 0 {
+            return invalid("network.ack_timeout_ms must be above 0");
+        }
+        if self.network.digest_interval_ms == 0 {
+            return invalid("network.digest_interval_ms must be above 0");
+        }
+        if self.hardware.ip.is_empty() {
+            return invalid("hardware.ip must not be empty");
+        }
+        if self.event_log.max_file_bytes == 0 {
+            return invalid("event_log.max_file_bytes must be above 0");
+        }
+        Ok(())
+    }
+}
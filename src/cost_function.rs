@@ -0,0 +1,270 @@
+use log::debug;
+use serde::*;
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::config::CostConfig;
+use crate::elev_controller::{ButtonType, Order, TravelDirection};
+
+/// What a cost function knows about the local elevator.
+pub struct CarState<'a> {
+    pub queue: &'a VecDeque<Order>,
+    /// Floor the car is at, -1 while between floors.
+    pub current_floor: isize,
+    pub last_floor: isize,
+    pub direction: TravelDirection,
+    pub elevator_id: u32,
+    /// Number of floors in the building.
+    pub floors: u8,
+}
+
+/// Decides how long this elevator waits before taking an order.
+/// The elevator whose delay runs out first takes the order, so a lower cost means a better fit.
+pub trait CostFunction {
+    fn cost(&self, order: &Order, car: &CarState) -> Duration;
+}
+
+/// The built in cost functions, selectable by name.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CostFunctionKind {
+    DongZafar,
+    NearestCar,
+    TimeToServe,
+    EnergySaving,
+}
+
+impl CostFunctionKind {
+    pub fn build(&self, constants: &CostConfig) -> Box<dyn CostFunction> {
+        let constants = constants.clone();
+        match self {
+            CostFunctionKind::DongZafar => Box::new(DongZafarCost{constants}),
+            CostFunctionKind::NearestCar => Box::new(NearestCarCost{constants}),
+            CostFunctionKind::TimeToServe => Box::new(TimeToServeCost{constants}),
+            CostFunctionKind::EnergySaving => Box::new(EnergySavingCost{constants}),
+        }
+    }
+}
+
+impl FromStr for CostFunctionKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "dong-zafar" => Ok(CostFunctionKind::DongZafar),
+            "nearest-car" => Ok(CostFunctionKind::NearestCar),
+            "time-to-serve" => Ok(CostFunctionKind::TimeToServe),
+            "energy-saving" => Ok(CostFunctionKind::EnergySaving),
+            _ => Err(format!("unknown cost function {:?}, expected dong-zafar, nearest-car, time-to-serve or energy-saving", name)),
+        }
+    }
+}
+
+impl fmt::Display for CostFunctionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CostFunctionKind::DongZafar => "dong-zafar",
+            CostFunctionKind::NearestCar => "nearest-car",
+            CostFunctionKind::TimeToServe => "time-to-serve",
+            CostFunctionKind::EnergySaving => "energy-saving",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+enum Direction {
+    Up,
+    Down
+}
+
+/// Position of the car used for distances, the last floor while between floors.
+fn position(car: &CarState) -> isize {
+    if car.current_floor >= 0 {
+        car.current_floor
+    } else {
+        car.last_floor
+    }
+}
+
+/// Mix of a Dong & Zafar direction score, queue length and elevator id. The original heuristic.
+pub struct DongZafarCost {
+    constants: CostConfig,
+}
+
+impl CostFunction for DongZafarCost {
+    fn cost(&self, order: &Order, car: &CarState) -> Duration {
+        // Number of floors, Distance between elevator and call, Direction of elevator
+        let c = &self.constants;
+        let elev_queue = car.queue;
+        let current_floor = car.current_floor;
+        let last_floor = car.last_floor;
+
+        let ip_score=car.elevator_id;
+        let n_floors = car.floors;
+        let direction = direction_of_call(current_floor, last_floor);
+
+        let mut number_of_elevator_orders = 0;
+        for _elev_orders in elev_queue {
+            number_of_elevator_orders += 1;
+        }
+        // Override variables used to manipulate cost function delay
+        let mut score_override=1;
+        let mut ip_score_override=1;
+        let mut long_queue_delay_override=1;
+
+        match elev_queue.front() {
+            Some(elev_current_doing) => {
+                // There are other orders in the elevator
+
+                let score; // Higher is better, must be > 0
+                // Based upon Dong & Zafar's Elevator Scheduling (http://www.columbia.edu/~cs2035/courses/ieor4405.S13/p14.pdf)
+                match elev_current_doing.order_type {
+                    ButtonType::CabCall => {
+                        if direction == Direction::Down && last_floor > order.floor as isize ||
+                        direction == Direction::Up && last_floor < order.floor as isize{
+                            // Elevator moving towards order
+                            score = (n_floors as isize + 2) - (order.floor as isize - last_floor).abs();
+                        }
+                        else{
+                            // Elevator moving away from order
+                            score = 1;
+                        }
+
+                    }
+                    ButtonType::HallDownCall => {
+                        if direction == Direction::Down && last_floor > order.floor as isize {
+                            // Elevator moving to order /w same direction
+                            score = (n_floors as isize + 2) - (order.floor as isize - last_floor).abs();
+                        } else if direction == Direction::Up && last_floor < order.floor as isize {
+                            // Elevator moving to order /w opposit direction
+                            score = (n_floors as isize + 1) - (order.floor as isize - last_floor).abs();
+                        } else {
+                            // Away from order
+                            score = 1;
+                        }
+                    }
+                    ButtonType::HallUpCall => {
+                        if direction == Direction::Up && last_floor < order.floor as isize {
+                            // Elevator moving to order /w same direction
+                            score = (n_floors as isize + 2) - (order.floor as isize - last_floor).abs();
+                        } else if direction == Direction::Down && last_floor > order.floor as isize {
+                            // Elevator moving to order /w opposit direction
+                            score = (n_floors as isize + 1) - (order.floor as isize - last_floor).abs();
+                        } else {
+                            // Away from order
+                            score = 1;
+                        }
+                    }
+                }
+                if order.order_type==ButtonType::CabCall || order.floor==elev_current_doing.floor{
+                    ip_score_override=0;
+                    long_queue_delay_override=0;
+                    score_override=0;
+                } 
+                let delay =c.dz_busy_base_ms as isize+(c.dz_score_ms as isize/score)* score_override+c.dz_queued_order_ms as isize * number_of_elevator_orders * long_queue_delay_override+c.id_delay_ms as isize * ip_score as isize * ip_score_override;
+                // basis_delay+score_delay    +        amount_of_order_delay      +                         unique_ip_delay
+                debug!(score = score, queued_orders = number_of_elevator_orders, delay_ms = delay; "Dong-Zafar delay of {:?}", order);
+
+                Duration::from_millis(delay as u64)
+            }
+            None => {
+
+                   //There is no other orders in the elevator
+                    let mut delay =c.dz_idle_base_ms;
+
+                    let current_order =order;
+                    if current_order.order_type==ButtonType::CabCall{
+                        delay=c.cab_call_delay_ms;
+                    }
+                    else{
+                        let distance_score =(current_floor-current_order.floor as isize).abs();
+                        delay=delay+c.dz_idle_floor_ms*distance_score as u64 +c.id_delay_ms*ip_score as u64;
+    
+                    }
+                    debug!(delay_ms = delay; "Dong-Zafar delay of {:?} for an idle car", order);
+                    Duration::from_millis(delay)
+            }
+        }
+    }
+}
+
+fn direction_of_call(going_to: isize, last_floor: isize) -> Direction { 
+    let dir;
+    if going_to - last_floor > 0 {
+        dir = Direction::Up;
+    } else {
+        dir = Direction::Down;
+    }
+    dir
+}
+
+/// Waits in proportion to the distance to the order only.
+pub struct NearestCarCost {
+    constants: CostConfig,
+}
+
+impl CostFunction for NearestCarCost {
+    fn cost(&self, order: &Order, car: &CarState) -> Duration {
+        let c = &self.constants;
+        if order.order_type == ButtonType::CabCall {
+            return Duration::from_millis(c.cab_call_delay_ms);
+        }
+        let distance = (order.floor as isize - position(car)).unsigned_abs() as u64;
+        Duration::from_millis(c.base_delay_ms + c.floor_delay_ms * distance + c.id_delay_ms * car.elevator_id as u64)
+    }
+}
+
+/// Waits in proportion to the estimated time until the car reaches the order,
+/// serving the queued orders first in the order they were taken.
+pub struct TimeToServeCost {
+    constants: CostConfig,
+}
+
+impl CostFunction for TimeToServeCost {
+    fn cost(&self, order: &Order, car: &CarState) -> Duration {
+        let c = &self.constants;
+        if order.order_type == ButtonType::CabCall {
+            return Duration::from_millis(c.cab_call_delay_ms);
+        }
+        let mut floor = position(car);
+        let mut time_ms = 0;
+        for queued in car.queue {
+            time_ms += (queued.floor as isize - floor).unsigned_abs() as u64 * c.floor_travel_ms + c.stop_ms;
+            floor = queued.floor as isize;
+        }
+        time_ms += (order.floor as isize - floor).unsigned_abs() as u64 * c.floor_travel_ms;
+        Duration::from_millis(c.base_delay_ms + time_ms / 2 + c.id_delay_ms * car.elevator_id as u64)
+    }
+}
+
+/// Prefers cars that already pass the order on their planned route, so fewer floors
+/// are travelled in total, and avoids starting idle cars.
+pub struct EnergySavingCost {
+    constants: CostConfig,
+}
+
+impl CostFunction for EnergySavingCost {
+    fn cost(&self, order: &Order, car: &CarState) -> Duration {
+        let c = &self.constants;
+        if order.order_type == ButtonType::CabCall {
+            return Duration::from_millis(c.cab_call_delay_ms);
+        }
+        let floor = position(car);
+        let target = order.floor as isize;
+        // Span of floors the car will travel anyway to serve its queue
+        let lowest = car.queue.iter().map(|queued| queued.floor as isize).fold(floor, std::cmp::min);
+        let highest = car.queue.iter().map(|queued| queued.floor as isize).fold(floor, std::cmp::max);
+        let extra_floors = if target < lowest {
+            lowest - target
+        } else if target > highest {
+            target - highest
+        } else {
+            0
+        } as u64;
+        let start_penalty = if car.queue.is_empty() && car.direction == TravelDirection::Stop { c.idle_start_ms } else { 0 };
+        Duration::from_millis(c.base_delay_ms + c.floor_delay_ms * extra_floors + start_penalty + c.id_delay_ms * car.elevator_id as u64)
+    }
+}
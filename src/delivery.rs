@@ -0,0 +1,152 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use crate::elev_controller::{ButtonEvent, RequestType};
+
+/// Whether the event has to be acknowledged by every peer. Requests are repeated until they are
+/// taken anyway, losing a `Taken` or `Complete` leaves the peers with a stale order.
+pub fn needs_acknowledgement(event: &ButtonEvent) -> bool {
+    match event.request {
+        RequestType::Request => false,
+        RequestType::Taken | RequestType::Complete => true,
+    }
+}
+
+/// An event of this elevator not yet acknowledged by every peer.
+#[derive(Debug, Clone)]
+pub struct Unacknowledged {
+    pub event: ButtonEvent,
+    /// Peers which have not acknowledged the event yet.
+    pub awaiting: BTreeSet<u32>,
+    pub first_sent: SystemTime,
+    pub retransmissions: u32,
+    last_sent: SystemTime,
+}
+
+/// Events of this elevator waiting to be acknowledged, retransmitted until every peer has
+/// acknowledged them or is dead.
+#[derive(Default)]
+pub struct PendingAcks {
+    pending: Vec<Unacknowledged>,
+}
+
+impl PendingAcks {
+    pub fn new() -> Self {
+        PendingAcks::default()
+    }
+
+    /// Starts waiting for `peers` to acknowledge the event. An event waiting for the same order
+    /// is superseded, a `Complete` makes the `Taken` before it pointless.
+    pub fn track<I: Iterator<Item = u32>>(&mut self, event: ButtonEvent, peers: I, now: SystemTime) {
+        self.pending.retain(|pending| pending.event.order != event.order);
+        let awaiting: BTreeSet<u32> = peers.collect();
+        if awaiting.is_empty() {
+            return;
+        }
+        self.pending.push(Unacknowledged{event, awaiting, first_sent: now, retransmissions: 0, last_sent: now});
+    }
+
+    pub fn acknowledged(&mut self, event: &ButtonEvent, by: u32) {
+        for pending in self.pending.iter_mut() {
+            if pending.event == *event {
+                pending.awaiting.remove(&by);
+            }
+        }
+        self.pending.retain(|pending| !pending.awaiting.is_empty());
+    }
+
+    /// Events to send again since they have not been acknowledged within `timeout`, with the peers still awaited.
+    /// Peers no longer in `live` are not waited for any more.
+    pub fn due(&mut self, now: SystemTime, timeout: Duration, live: &BTreeSet<u32>) -> Vec<Unacknowledged> {
+        for pending in self.pending.iter_mut() {
+            pending.awaiting.retain(|id| live.contains(id));
+        }
+        self.pending.retain(|pending| !pending.awaiting.is_empty());
+        let mut due = vec![];
+        for pending in self.pending.iter_mut() {
+            if now.duration_since(pending.last_sent).unwrap_or_default() >= timeout {
+                pending.last_sent = now;
+                pending.retransmissions += 1;
+                due.push(pending.clone());
+            }
+        }
+        due
+    }
+
+    pub fn unacknowledged(&self) -> &[Unacknowledged] {
+        &self.pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elev_controller::{ButtonType, Order, OrderId};
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn event(request: RequestType, floor: u8) -> ButtonEvent {
+        ButtonEvent{request, order: Order{floor, order_type: ButtonType::HallUpCall}, origin: 1,
+                    id: OrderId{origin: 1, sequence: 1}, version: 0, releases: 0, created_ms: 0}
+    }
+
+    fn live(peers: &[u32]) -> BTreeSet<u32> {
+        peers.iter().cloned().collect()
+    }
+
+    #[test]
+    fn waits_until_every_peer_has_acknowledged() {
+        let start = SystemTime::UNIX_EPOCH;
+        let taken = event(RequestType::Taken, 2);
+        let mut pending = PendingAcks::new();
+        pending.track(taken.clone(), vec![2, 3].into_iter(), start);
+        assert!(pending.due(start + TIMEOUT / 2, TIMEOUT, &live(&[2, 3])).is_empty());
+        pending.acknowledged(&taken, 2);
+        let due = pending.due(start + TIMEOUT, TIMEOUT, &live(&[2, 3]));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].event, taken);
+        assert_eq!(due[0].awaiting, live(&[3]));
+        assert_eq!(due[0].retransmissions, 1);
+        pending.acknowledged(&taken, 3);
+        assert!(pending.unacknowledged().is_empty());
+    }
+
+    #[test]
+    fn acknowledgement_of_another_event_changes_nothing() {
+        let start = SystemTime::UNIX_EPOCH;
+        let mut pending = PendingAcks::new();
+        pending.track(event(RequestType::Taken, 2), vec![2].into_iter(), start);
+        pending.acknowledged(&event(RequestType::Taken, 3), 2);
+        pending.acknowledged(&event(RequestType::Complete, 2), 2);
+        assert_eq!(pending.unacknowledged().len(), 1);
+    }
+
+    #[test]
+    fn complete_supersedes_taken() {
+        let start = SystemTime::UNIX_EPOCH;
+        let mut pending = PendingAcks::new();
+        pending.track(event(RequestType::Taken, 2), vec![2].into_iter(), start);
+        pending.track(event(RequestType::Complete, 2), vec![2].into_iter(), start);
+        let unacknowledged = pending.unacknowledged();
+        assert_eq!(unacknowledged.len(), 1);
+        assert_eq!(unacknowledged[0].event.request, RequestType::Complete);
+    }
+
+    #[test]
+    fn stops_waiting_for_dead_peers() {
+        let start = SystemTime::UNIX_EPOCH;
+        let mut pending = PendingAcks::new();
+        pending.track(event(RequestType::Taken, 2), vec![2, 3].into_iter(), start);
+        assert_eq!(pending.due(start + TIMEOUT, TIMEOUT, &live(&[3]))[0].awaiting, live(&[3]));
+        assert!(pending.due(start + TIMEOUT * 2, TIMEOUT, &live(&[])).is_empty());
+        assert!(pending.unacknowledged().is_empty());
+    }
+
+    #[test]
+    fn nothing_to_wait_for_without_peers() {
+        let mut pending = PendingAcks::new();
+        pending.track(event(RequestType::Taken, 2), vec![].into_iter(), SystemTime::UNIX_EPOCH);
+        assert!(pending.unacknowledged().is_empty());
+    }
+}
@@ -0,0 +1,11 @@
+use serde::*;
+
+use crate::order_state::ReplicatedOrder;
+
+/// The order book of one elevator, broadcast periodically and merged into the books of the
+/// others, so elevators that missed events or were cut off from the others converge again.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Digest {
+    pub elevator_id: u32,
+    pub orders: Vec<ReplicatedOrder>,
+}
@@ -1,37 +1,127 @@
 use elevator_driver::elev_driver::*;
-use network_rust::bcast::BcastTransmitter;
+use log::{debug, error, info, trace, warn};
+use std::fmt;
 use std::io;
 use serde::*;
-use std::thread;
-use std::thread::sleep;
-use std::sync::mpsc::Sender;
 use std::time::Duration;
 use std::time::SystemTime;
 use std::collections::VecDeque;
 
-pub struct ElevController {
+use crate::building::Building;
+use crate::clock::SharedClock;
+use crate::config::ElevatorConfig;
+use crate::elev_hardware::ElevHardware;
+use crate::error::{ElevError, ElevResult};
+use crate::message_bus::MessageBus;
+use crate::metrics::{Metrics, SharedMetrics};
+use crate::network::MessageTransmitter;
+use crate::protocol::{Envelope, Message, ProtocolError};
+
+pub struct ElevController<H: ElevHardware> {
     queue: VecDeque<Order>,
-    driver: ElevIo,
+    /// The `Taken` event of every order in the queue, which says what press the order serves.
+    queued: Vec<ButtonEvent>,
+    /// Sequence number of the last press of a button in this car.
+    order_sequence: u64,
+    driver: H,
     door_state: DoorState,
     previous_floor: Floor,
-    internal_msg_sender: Sender<ButtonEvent>,
     elevator_id: u32,
-    udp_broadcast_port: u16,
+    bus: MessageBus,
+    clock: SharedClock,
+    link: HardwareLink,
+    direction: TravelDirection,
+    service_mode: ServiceMode,
+    /// Direction the car is serving orders in, kept while it stops at floors.
+    sweep: TravelDirection,
+    /// The stop button has been pressed, the car stands still until it is pressed again.
+    emergency_stop: bool,
+    /// Stop button signal read last time, presses are detected on the rising edge.
+    stop_button_held: bool,
+    /// Call buttons held at the last reading, presses are detected on the rising edge like the stop button.
+    buttons_held: Vec<Order>,
+    watchdog: TravelWatchdog,
+    timing: ElevatorConfig,
+    building: Building,
+    metrics: SharedMetrics,
+}
+
+/// Notices a car which does not reach a new floor while the motor is running.
+struct TravelWatchdog {
+    /// Floor sensor reading last time, -1 between floors.
+    last_floor_signal: isize,
+    /// When the floor sensor last changed, or the motor was last stopped.
+    last_change: SystemTime,
+    stuck: Option<StuckCar>,
+}
+
+/// A car taken out of service by the travel watchdog.
+/// The motor is run for a short while now and then to find out if the car has come loose.
+struct StuckCar {
+    direction: TravelDirection,
+    next_probe: SystemTime,
+    probe_until: SystemTime,
+}
+
+/// The order in which the queue is served.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServiceMode {
+    /// Orders are served in the order they were taken, only cab calls on the way are served early.
+    Fifo,
+    /// The car serves every order in its direction of travel before turning, like a collective control.
+    Collective,
+}
+
+/// Whether the hardware can be driven, or when to next try reconnecting to it.
+enum HardwareLink {
+    Connected,
+    Lost {
+        retry_at: SystemTime,
+        backoff: Duration,
+    },
 }
 
 struct DoorState {
     timestamp_open: SystemTime,
-    complete: bool
+    complete: bool,
+    /// When the current obstruction of the door started.
+    obstructed_since: Option<SystemTime>,
+    /// The door has been obstructed for too long, the car is out of service until it is cleared.
+    obstruction_fault: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct ButtonEvent {
     pub request: RequestType,
     pub order: Order,
-    pub origin: u32
+    pub origin: u32,
+    /// The press of the button the event is about.
+    pub id: OrderId,
+    /// Version and releases of the order a `Taken` or `Complete` event was made for, see `OrderState`.
+    /// A request is made for no version in particular, each elevator starts one for the press, so they are 0 in requests.
+    pub version: u32,
+    pub releases: u32,
+    /// When the button was pressed, in milliseconds since the unix epoch on the clock of the elevator it was pressed in.
+    pub created_ms: u64,
+}
+
+/// Identifies one press of a button among every elevator.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct OrderId {
+    /// Elevator the button was pressed in.
+    pub origin: u32,
+    /// Counts the presses in that elevator. It starts from the time the elevator was started
+    /// in microseconds, so it keeps increasing across restarts.
+    pub sequence: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl fmt::Display for OrderId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.origin, self.sequence)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum RequestType {
     Request,
     Taken,
@@ -44,6 +134,14 @@ pub struct Order {
     pub order_type: ButtonType,
 }
 
+/// Direction the motor was last driven in.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum TravelDirection {
+    Up,
+    Down,
+    Stop
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum ButtonType {
     CabCall,
@@ -52,146 +150,354 @@ pub enum ButtonType {
 }
 
 pub const BCAST_PORT: u16 = 26665;
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(10);
 
-fn init_elevator(elev_io: &ElevIo) {
+fn init_elevator<H: ElevHardware>(elev_io: &H) -> io::Result<()> {
     loop {
-        match elev_io.get_floor_signal().unwrap() {
+        match elev_io.get_floor_signal()? {
             Floor::At(_) => {
-                elev_io.set_motor_dir(MotorDir::Stop).unwrap();
-                break;
+                elev_io.set_motor_dir(MotorDir::Stop)?;
+                return Ok(());
             }
             Floor::Between => {
-                elev_io.set_motor_dir(MotorDir::Down).unwrap();
+                elev_io.set_motor_dir(MotorDir::Down)?;
             }
         }
     }
 }
 
+fn is_high(signal: Signal) -> bool {
+    match signal {
+        Signal::High => true,
+        Signal::Low => false,
+    }
+}
 
-impl ElevController {
-    pub fn new(internal_message_sender: Sender<ButtonEvent>, elevator_id: u32, udp_broadcast_port: u16 , elevator_ip: &str, elevator_port: u16) -> io::Result<Self> {
+fn drive<H: ElevHardware>(elev_io: &H, direction: &mut TravelDirection, new_direction: TravelDirection) -> io::Result<()> {
+    let motor_dir = match new_direction {
+        TravelDirection::Up => MotorDir::Up,
+        TravelDirection::Down => MotorDir::Down,
+        TravelDirection::Stop => MotorDir::Stop,
+    };
+    elev_io.set_motor_dir(motor_dir)?;
+    *direction = new_direction;
+    Ok(())
+}
+
+impl<H: ElevHardware> ElevController<H> {
+    pub fn new(elevator_id: u32, transmitter: Box<dyn MessageTransmitter>, elev_driver: H, clock: SharedClock) -> ElevResult<Self> {
         let que_obj: VecDeque<Order> = VecDeque::new();
-        let elev_driver = ElevIo::new(elevator_ip, elevator_port).expect("Connecting to elevator failed");
-        init_elevator(&elev_driver);
-        elev_driver.set_all_light(Light::Off).unwrap();
-        let sys_time = SystemTime::now();
-        let init_door_state = DoorState{timestamp_open: sys_time, complete: true} ;
-        let current_floor = elev_driver.get_floor_signal().unwrap();
-        let controller = ElevController{queue: que_obj, driver:elev_driver, door_state:  init_door_state, previous_floor: current_floor, internal_msg_sender: internal_message_sender, elevator_id: elevator_id, udp_broadcast_port: udp_broadcast_port};
+        init_elevator(&elev_driver)?;
+        elev_driver.set_all_light(Light::Off)?;
+        elev_driver.set_stop_light(Light::Off)?;
+        let sys_time = clock.now();
+        let init_door_state = DoorState{timestamp_open: sys_time, complete: true, obstructed_since: None, obstruction_fault: false};
+        let current_floor = elev_driver.get_floor_signal()?;
+        let watchdog = TravelWatchdog{last_floor_signal: -1, last_change: sys_time, stuck: None};
+        let order_sequence = sys_time.duration_since(SystemTime::UNIX_EPOCH).map(|time| time.as_micros() as u64).unwrap_or(0);
+        let controller = ElevController{queue: que_obj, queued: vec![], order_sequence, driver:elev_driver, door_state:  init_door_state, previous_floor: current_floor, elevator_id, bus: MessageBus::new(elevator_id, transmitter), clock, link: HardwareLink::Connected, direction: TravelDirection::Stop, service_mode: ServiceMode::Collective, sweep: TravelDirection::Stop,
+                                       emergency_stop: false, stop_button_held: false, buttons_held: vec![], watchdog, timing: ElevatorConfig::default(),
+                                       building: Building::default(), metrics: Metrics::shared()};
         Ok(controller)
     }
     
-    pub fn handle_order(&mut self) {
-        match self.driver.get_floor_signal()
-                    .expect("Get FloorSignal failed") {
+    pub fn set_timing(&mut self, timing: ElevatorConfig) {
+        self.timing = timing;
+    }
+
+    pub fn set_building(&mut self, building: Building) {
+        self.building = building;
+    }
+
+    pub fn set_metrics(&mut self, metrics: SharedMetrics) {
+        self.bus.set_metrics(metrics.clone());
+        self.metrics = metrics;
+    }
+
+    pub fn set_service_mode(&mut self, service_mode: ServiceMode) {
+        self.service_mode = service_mode;
+    }
+
+    pub fn handle_order(&mut self) -> ElevResult<()> {
+        self.handle_stop_button()?;
+        if self.emergency_stop {
+            return Ok(());
+        }
+        if self.check_travel_watchdog()? {
+            return Ok(());
+        }
+        match self.driver.get_floor_signal()? {
             Floor::At(c_floor) => {
                 if !self.door_state.complete {
-                    match self.door_state.timestamp_open.elapsed() {
-                        Ok(time) => {
-                            if time > Duration::from_secs(3) {                            
-                                self.driver.set_door_light(Light::Off).unwrap();
-                                self.door_state.complete = true;
-                                //println!("[elev_controller] Door closed");
-                            }
-                        }
-                        Err(e) => {
-                            println!("[elev_controller]: Systime error occured {:?}", e);
-                        }
-                    }
+                    self.handle_door()?;
                 } else {
-                    self.driver.set_floor_light(Floor::At(c_floor)).unwrap();
-                    let mut clear_orders_at_floor: std::vec::Vec<Order> = vec![]; //used to clear all orders at the floor the elevator arrives at
-                    let queue_clone=self.queue.clone();
-                    match self.queue.front() {
-                        Some(order) => {
-                            //println!("[elev_controller] C: {:?} O: {:?}", c_floor, order.floor);   
-                            if c_floor > order.floor{
-                                self.driver.set_motor_dir(MotorDir::Down).expect("Set MotorDir failed");
-                            }
-                            if c_floor < order.floor{
-                                self.driver.set_motor_dir(MotorDir::Up).expect("Set MotorDir failed");
-                            }
-                            if c_floor == order.floor{
-                                self.driver.set_motor_dir(MotorDir::Stop).expect("Set MotorDir failed");
-                                for other_order in queue_clone{
-                                    if other_order.floor == c_floor{
-                                        clear_orders_at_floor.push(other_order.clone());
-                                    }
-                                }
-                                self.complete_order_signal(order);
-                                self.open_door();
-                            } else {
-                                // Completes cabcall orders which are on your way to the current order.
-                                for other_order in queue_clone.clone(){
-                                    match other_order.order_type{
-                                        ButtonType::CabCall => {
-                                            if other_order.floor == c_floor{
-                                                self.driver.set_motor_dir(MotorDir::Stop).expect("Set MotorDir failed");
-                                                clear_orders_at_floor.push(other_order.clone());
-                                                self.open_door();
-                                                for other_order in queue_clone.clone(){
-                                                    match other_order.order_type{
-                                                        ButtonType::CabCall =>{}
-                                                        _ => {
-                                                            if other_order.floor == c_floor{
-                                                                clear_orders_at_floor.push(other_order.clone());
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                            }
-                            match self.previous_floor {
-                                Floor::At(p_floor) => {
-                                    if p_floor != c_floor {
-                                        self.previous_floor = self.driver.get_floor_signal().unwrap();
-                                    }
-                                }
-                                Floor::Between => {
-                                    self.previous_floor = self.driver.get_floor_signal().unwrap();
-                                }
-                            }
-                        }
-                        None => {
-                            self.driver.set_motor_dir(MotorDir::Stop).unwrap();
-                        }
+                    self.driver.set_floor_light(Floor::At(c_floor))?;
+                    // The door close button does nothing while the door is closed, read it to clear the press
+                    self.driver.get_door_close_signal()?;
+                    if is_high(self.driver.get_door_open_signal()?) && self.direction == TravelDirection::Stop {
+                        self.open_door()?;
+                        return Ok(());
                     }
-                    for order in clear_orders_at_floor {
-                        let index = self.queue.iter().position(|x| *x == order).unwrap();
-                        self.queue.remove(index);
-                        self.complete_order_signal(&order);
+                    match self.service_mode {
+                        ServiceMode::Fifo => self.serve_fifo(c_floor)?,
+                        ServiceMode::Collective => self.serve_collective(c_floor)?,
                     }
                 }
             }
+            Floor::Between if self.service_mode == ServiceMode::Collective && self.sweep != TravelDirection::Stop => {
+                drive(&self.driver, &mut self.direction, self.sweep)?;
+            }
             Floor::Between => {
                 match self.queue.front() {
                     Some(order) => {
                         if self.get_previous_floor() > order.floor as isize{
-                            self.driver.set_motor_dir(MotorDir::Down).expect("Set MotorDir failed");
+                            drive(&self.driver, &mut self.direction, TravelDirection::Down)?;
                         }
                         if self.get_previous_floor() < order.floor as isize{
-                            self.driver.set_motor_dir(MotorDir::Up).expect("Set MotorDir failed");
+                            drive(&self.driver, &mut self.direction, TravelDirection::Up)?;
                         }
                     }
                     None => {
-                        self.driver.set_motor_dir(MotorDir::Down).unwrap();
+                        drive(&self.driver, &mut self.direction, TravelDirection::Down)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Enters the emergency stop on a press of the stop button, and leaves it on the next press.
+    /// The queue is kept, servicing continues where it left off after the reset.
+    fn handle_stop_button(&mut self) -> ElevResult<()> {
+        let held = is_high(self.driver.get_stop_signal()?);
+        let pressed = held && !self.stop_button_held;
+        self.stop_button_held = held;
+        if !pressed {
+            return Ok(());
+        }
+        if self.emergency_stop {
+            info!("Emergency stop reset");
+            self.driver.set_stop_light(Light::Off)?;
+            self.emergency_stop = false;
+        } else {
+            warn!("Emergency stop, press the stop button again to reset");
+            drive(&self.driver, &mut self.direction, TravelDirection::Stop)?;
+            self.driver.set_stop_light(Light::On)?;
+            self.emergency_stop = true;
+        }
+        Ok(())
+    }
+
+    /// Stops the car if the motor has run for too long without the floor sensor changing,
+    /// and puts it back in service when the sensor changes again.
+    /// Returns true while the car is stuck and must not be driven.
+    fn check_travel_watchdog(&mut self) -> ElevResult<bool> {
+        let floor_signal = self.get_current_floor()?;
+        let now = self.clock.now();
+        if floor_signal != self.watchdog.last_floor_signal {
+            self.watchdog.last_floor_signal = floor_signal;
+            self.watchdog.last_change = now;
+            if self.watchdog.stuck.take().is_some() {
+                info!("Car is moving again, back in service");
+            }
+            return Ok(false);
+        }
+        match self.watchdog.stuck {
+            None => {
+                if self.direction == TravelDirection::Stop {
+                    self.watchdog.last_change = now;
+                    return Ok(false);
+                }
+                let since_change = self.clock.elapsed(&mut self.watchdog.last_change)?;
+                if since_change <= self.timing.travel_timeout() {
+                    return Ok(false);
+                }
+                error!("No floor reached in {:?} while driving {:?}, taking the elevator out of service", since_change, self.direction);
+                let direction = self.direction;
+                drive(&self.driver, &mut self.direction, TravelDirection::Stop)?;
+                self.watchdog.stuck = Some(StuckCar{direction, next_probe: now + self.timing.stuck_probe_interval(), probe_until: now});
+            }
+            Some(ref mut stuck) => {
+                if now >= stuck.next_probe {
+                    drive(&self.driver, &mut self.direction, stuck.direction)?;
+                    stuck.probe_until = now + self.timing.stuck_probe_time();
+                    stuck.next_probe = now + self.timing.stuck_probe_interval();
+                } else if now >= stuck.probe_until && self.direction != TravelDirection::Stop {
+                    drive(&self.driver, &mut self.direction, TravelDirection::Stop)?;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Keeps the door open while it is obstructed or the door open button is pressed,
+    /// and closes it when the open time has passed or the door close button is pressed.
+    fn handle_door(&mut self) -> ElevResult<()> {
+        let now = self.clock.now();
+        if is_high(self.driver.get_obstr_signal()?) {
+            self.door_state.timestamp_open = now;
+            let mut since = self.door_state.obstructed_since.unwrap_or(now);
+            let obstructed_for = self.clock.elapsed(&mut since)?;
+            self.door_state.obstructed_since = Some(since);
+            if obstructed_for > self.timing.door_obstruction_fault() && !self.door_state.obstruction_fault {
+                warn!("Door obstructed for {:?}, taking the elevator out of service", obstructed_for);
+                self.door_state.obstruction_fault = true;
+            }
+            return Ok(());
+        }
+        if self.door_state.obstructed_since.take().is_some() && self.door_state.obstruction_fault {
+            info!("Door obstruction cleared, back in service");
+            self.door_state.obstruction_fault = false;
+        }
+        if is_high(self.driver.get_door_open_signal()?) {
+            self.door_state.timestamp_open = now;
+        }
+        let close_early = is_high(self.driver.get_door_close_signal()?);
+        if close_early || self.clock.elapsed(&mut self.door_state.timestamp_open)? > self.timing.door_open_time() {
+            self.driver.set_door_light(Light::Off)?;
+            self.door_state.complete = true;
+            debug!("Door closed");
+        }
+        Ok(())
+    }
+
+    /// Serves the queue in the order the orders were taken, stopping only for cab calls on the way.
+    fn serve_fifo(&mut self, c_floor: u8) -> ElevResult<()> {
+        let mut clear_orders_at_floor: std::vec::Vec<Order> = vec![]; //used to clear all orders at the floor the elevator arrives at
+        let queue_clone=self.queue.clone();
+        match self.queue.front() {
+            Some(order) => {
+                trace!(floor = c_floor, order_floor = order.floor; "Serving the front of the queue");
+                if c_floor > order.floor{
+                    drive(&self.driver, &mut self.direction, TravelDirection::Down)?;
+                }
+                if c_floor < order.floor{
+                    drive(&self.driver, &mut self.direction, TravelDirection::Up)?;
+                }
+                if c_floor == order.floor{
+                    drive(&self.driver, &mut self.direction, TravelDirection::Stop)?;
+                    for other_order in queue_clone{
+                        if other_order.floor == c_floor{
+                            clear_orders_at_floor.push(other_order.clone());
+                        }
+                    }
+                    let order = order.clone();
+                    self.complete_order_signal(&order);
+                    self.open_door()?;
+                } else {
+                    // Completes cabcall orders which are on your way to the current order.
+                    for other_order in queue_clone.clone(){
+                        match other_order.order_type{
+                            ButtonType::CabCall => {
+                                if other_order.floor == c_floor{
+                                    drive(&self.driver, &mut self.direction, TravelDirection::Stop)?;
+                                    clear_orders_at_floor.push(other_order.clone());
+                                    self.open_door()?;
+                                    for other_order in queue_clone.clone(){
+                                        match other_order.order_type{
+                                            ButtonType::CabCall =>{}
+                                            _ => {
+                                                if other_order.floor == c_floor{
+                                                    clear_orders_at_floor.push(other_order.clone());
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
                     }
                 }
+                self.update_previous_floor(c_floor)?;
+            }
+            None => {
+                drive(&self.driver, &mut self.direction, TravelDirection::Stop)?;
             }
         }
+        for order in clear_orders_at_floor {
+            if let Some(index) = self.queue.iter().position(|x| *x == order) {
+                self.queue.remove(index);
+                self.complete_order_signal(&order);
+            }
+        }
+        Ok(())
     }
 
-    pub fn get_current_floor(&self) -> isize { 
-        match self.driver.get_floor_signal().unwrap() {
+    /// Keeps travelling in one direction, stopping for every cab call and every hall call
+    /// in the direction of travel, and only turns when no orders are left ahead.
+    fn serve_collective(&mut self, c_floor: u8) -> ElevResult<()> {
+        self.update_previous_floor(c_floor)?;
+        if self.queue.is_empty() {
+            self.sweep = TravelDirection::Stop;
+            drive(&self.driver, &mut self.direction, TravelDirection::Stop)?;
+            return Ok(());
+        }
+        let floor = c_floor as isize;
+        let ahead = |queue: &VecDeque<Order>, sweep: TravelDirection| queue.iter().any(|order| match sweep {
+            TravelDirection::Up => order.floor as isize > floor,
+            TravelDirection::Down => (order.floor as isize) < floor,
+            TravelDirection::Stop => false,
+        });
+        if !ahead(&self.queue, self.sweep) {
+            // Nothing left in the direction of travel, turn towards the oldest order
+            let oldest = self.queue[0].floor;
+            self.sweep = if oldest > c_floor {
+                TravelDirection::Up
+            } else if oldest < c_floor || self.queue.iter().any(|order| order.floor == c_floor && order.order_type == ButtonType::HallDownCall) {
+                TravelDirection::Down
+            } else {
+                TravelDirection::Up
+            };
+        }
+        let continues = ahead(&self.queue, self.sweep);
+        let sweep = self.sweep;
+        let clear_orders_at_floor: Vec<Order> = self.queue.iter()
+            .filter(|order| order.floor == c_floor)
+            .filter(|order| match order.order_type {
+                ButtonType::CabCall => true,
+                ButtonType::HallUpCall => sweep == TravelDirection::Up || !continues,
+                ButtonType::HallDownCall => sweep == TravelDirection::Down || !continues,
+            })
+            .cloned()
+            .collect();
+        if clear_orders_at_floor.is_empty() {
+            drive(&self.driver, &mut self.direction, sweep)?;
+            return Ok(());
+        }
+        drive(&self.driver, &mut self.direction, TravelDirection::Stop)?;
+        self.open_door()?;
+        for order in clear_orders_at_floor {
+            if let Some(index) = self.queue.iter().position(|x| *x == order) {
+                self.queue.remove(index);
+                self.complete_order_signal(&order);
+            }
+        }
+        Ok(())
+    }
+
+    fn update_previous_floor(&mut self, c_floor: u8) -> ElevResult<()> {
+        match self.previous_floor {
+            Floor::At(p_floor) => {
+                if p_floor != c_floor {
+                    self.previous_floor = self.driver.get_floor_signal()?;
+                }
+            }
+            Floor::Between => {
+                self.previous_floor = self.driver.get_floor_signal()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_current_floor(&self) -> ElevResult<isize> {
+        match self.driver.get_floor_signal()? {
             Floor::At(num) => {
-                num as isize
+                Ok(num as isize)
             }
             Floor::Between => {
-                -1
+                Ok(-1)
             }
         }
     }
@@ -207,84 +513,188 @@ impl ElevController {
         }
     }
 
-    pub fn broadcast_active_buttons(&mut self) {
-        for floor in 0..N_FLOORS {
-            match self.driver.get_button_signal(Button::Internal(Floor::At(floor))).unwrap() {
-                Signal::High => {
-                    let order = Order{floor: floor, order_type: ButtonType::CabCall};
-                    self.broadcast_order(order, RequestType::Request, self.elevator_id);
-                }
-                Signal::Low => {
+    pub fn get_direction(&self) -> TravelDirection {
+        self.direction
+    }
 
-                }
+    pub fn is_door_open(&self) -> bool {
+        !self.door_state.complete
+    }
+
+    /// Whether the car is unable to serve orders, its hall calls should then be served by others.
+    pub fn is_out_of_service(&self) -> bool {
+        self.door_state.obstruction_fault || self.emergency_stop || self.watchdog.stuck.is_some()
+    }
+
+    /// Requests an order for every button pressed since the last call. The buttons report whether
+    /// they are held, so a button only makes a new press when it goes from released to held.
+    pub fn broadcast_active_buttons(&mut self) -> ElevResult<()> {
+        let mut held = vec![];
+        for floor in 0..self.building.floors {
+            let mut buttons = vec![(Button::Internal(Floor::At(floor)), ButtonType::CabCall)];
+            if self.building.has_up_button(floor) {
+                buttons.push((Button::CallUp(Floor::At(floor)), ButtonType::HallUpCall));
             }
-            if floor != (N_FLOORS-1) {
-                match self.driver.get_button_signal(Button::CallUp(Floor::At(floor))).expect("Unable to retrive hall up") {
-                    Signal::High => {
-                        let order = Order{floor: floor, order_type: ButtonType::HallUpCall};
-                        self.broadcast_order(order, RequestType::Request, self.elevator_id);
-                    }
-                    Signal::Low => {
-    
-                    }
-                }
+            if self.building.has_down_button(floor) {
+                buttons.push((Button::CallDown(Floor::At(floor)), ButtonType::HallDownCall));
             }
-            if floor != 0 {
-                match self.driver.get_button_signal(Button::CallDown(Floor::At(floor))).expect("Unable to retrive hall down") {
-                    Signal::High => {
-                        let order = Order{floor: floor, order_type: ButtonType::HallDownCall};
-                        self.broadcast_order(order, RequestType::Request, self.elevator_id);
-                    }
-                    Signal::Low => {
-    
-                    }
+            for (button, order_type) in buttons {
+                if let Signal::High = self.driver.get_button_signal(button)? {
+                    held.push(Order{floor, order_type});
                 }
-            } 
+            }
         }
+        let pressed: Vec<Order> = held.iter().filter(|order| !self.buttons_held.contains(order)).cloned().collect();
+        self.buttons_held = held;
+        for order in pressed {
+            self.request_order(order);
+        }
+        Ok(())
     }
 
-    fn open_door(&mut self) {
-        self.driver.set_door_light(Light::On).unwrap();
+    fn open_door(&mut self) -> ElevResult<()> {
+        self.driver.set_door_light(Light::On)?;
         self.door_state.complete = false;
-        self.door_state.timestamp_open = SystemTime::now();
+        self.door_state.timestamp_open = self.clock.now();
+        self.metrics.door_cycle();
+        Ok(())
+    }
 
+    fn complete_order_signal(&mut self, order: &Order) {
+        let taken = match self.queued.iter().position(|event| event.order == *order) {
+            Some(index) => self.queued.remove(index),
+            None => {
+                debug!("{:?} has already been completed", order);
+                return;
+            }
+        };
+        self.broadcast_order(ButtonEvent{request: RequestType::Complete, ..taken});
+    }
+
+    /// Queues the order of a `Taken` event of this elevator, and tells the others it is taken.
+    pub fn add_order(&mut self, taken: ButtonEvent) {
+        self.queue.push_back(taken.order.clone());
+        self.queued.push(taken.clone());
+        self.broadcast_order(taken);
+    }
+
+    /// Broadcasts a request for an order after a button was pressed in this car.
+    /// Every press gets an id of its own.
+    pub fn request_order(&mut self, order: Order) {
+        self.order_sequence += 1;
+        let id = OrderId{origin: self.elevator_id, sequence: self.order_sequence};
+        let created_ms = self.clock.now().duration_since(SystemTime::UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or(0);
+        self.broadcast_order(ButtonEvent{request: RequestType::Request, order, origin: self.elevator_id, id, version: 0, releases: 0, created_ms});
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match self.link {
+            HardwareLink::Connected => true,
+            HardwareLink::Lost{..} => false,
+        }
+    }
+
+    /// Stops driving the car until the link to the hardware is re-established by `try_reconnect`.
+    pub fn hardware_lost(&mut self) {
+        if self.is_connected() {
+            error!("Lost connection to elevator hardware");
+            self.link = HardwareLink::Lost{retry_at: self.clock.now() + RECONNECT_BACKOFF_MIN, backoff: RECONNECT_BACKOFF_MIN};
+        }
     }
 
-    fn complete_order_signal(&self, order: &Order) {
-        let order_copy = order.clone();
-        self.broadcast_order(order_copy, RequestType::Complete, self.elevator_id);
+    /// Reconnects to the hardware if the backoff has passed and brings the car back to a floor.
+    /// Returns true once the car can be driven again, the queue is kept as it was.
+    pub fn try_reconnect(&mut self) -> bool {
+        let backoff = match self.link {
+            HardwareLink::Connected => return true,
+            HardwareLink::Lost{retry_at, backoff} => {
+                if self.clock.now() < retry_at {
+                    return false;
+                }
+                backoff
+            }
+        };
+        match self.reinitialise() {
+            Ok(()) => {
+                info!("Reconnected to elevator hardware");
+                self.link = HardwareLink::Connected;
+                true
+            }
+            Err(e) => {
+                let backoff = std::cmp::min(backoff * 2, RECONNECT_BACKOFF_MAX);
+                warn!("Reconnect failed: {}, retrying in {:?}", e, backoff);
+                self.link = HardwareLink::Lost{retry_at: self.clock.now() + backoff, backoff};
+                false
+            }
+        }
     }
 
-    pub fn add_order(&mut self, order: Order) {
-        let order_copy = order.clone();
-        self.queue.push_back(order);
-        self.broadcast_order(order_copy, RequestType::Taken, self.elevator_id);
+    fn reinitialise(&mut self) -> io::Result<()> {
+        self.driver.reconnect()?;
+        if self.emergency_stop {
+            // The car must not move until the stop is reset
+            self.driver.set_motor_dir(MotorDir::Stop)?;
+        } else {
+            init_elevator(&self.driver)?;
+        }
+        self.driver.set_all_light(Light::Off)?;
+        self.driver.set_stop_light(if self.emergency_stop { Light::On } else { Light::Off })?;
+        self.previous_floor = self.driver.get_floor_signal()?;
+        self.door_state.complete = true;
+        self.door_state.obstructed_since = None;
+        self.door_state.obstruction_fault = false;
+        self.direction = TravelDirection::Stop;
+        self.sweep = TravelDirection::Stop;
+        Ok(())
+    }
+
+    /// Stops the car before shutting down. Errors are ignored since the hardware may be gone.
+    pub fn safe_stop(&self) {
+        let _ = self.driver.set_motor_dir(MotorDir::Stop);
+        let _ = self.driver.set_door_light(Light::Off);
     }
 
     pub fn delete_order(&mut self, order: &Order) {
+        self.queued.retain(|event| event.order != *order);
         match self.queue.iter().position(|x| *x == *order){
             Some(index) => {
                 self.queue.remove(index);
             },
             None => {
-                println!("[elev_controller] Nothing to delete")
+                debug!("{:?} is not in the queue, nothing to delete", order)
             }
         }
     }
 
-    pub fn broadcast_order(&self, order: Order, request: RequestType, origin: u32) {
-        let broadcast = BcastTransmitter::new(self.udp_broadcast_port).unwrap();
-        let data_block_internal = ButtonEvent{request: request, order: order, origin:origin };
-        //println!("{:?}: Broadcasting {:?}", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap() , data_block_internal);
-        let data_block_network = data_block_internal.clone();
-        self.internal_msg_sender.send(data_block_internal).unwrap();
-        thread::spawn(move || {
-            for _ in 0..3 {
-                broadcast.transmit(&data_block_network).unwrap();
-                sleep(Duration::from_millis(50));
-            }
-        });
-        
+    /// Delivers a button event to the task manager of this elevator and broadcasts it to the others.
+    /// A failed broadcast is only logged, the event reaches this elevator anyway and the others
+    /// catch up from retransmissions and digests, so the car keeps serving its orders without a network.
+    pub fn broadcast_order(&mut self, data_block_internal: ButtonEvent) {
+        debug!(request:? = data_block_internal.request, origin = data_block_internal.origin, id:% = data_block_internal.id; "Broadcasting {:?}", data_block_internal.order);
+        let (request, order) = (data_block_internal.request.clone(), data_block_internal.order.clone());
+        if let Err(e) = self.bus.publish(data_block_internal) {
+            warn!(request:? = request; "Unable to broadcast {:?}: {}", order, e);
+        }
+    }
+
+    /// Sends a message to the other elevators only.
+    pub fn transmit(&mut self, message: &Message) -> ElevResult<()> {
+        self.bus.transmit(message).map_err(ElevError::Network)
+    }
+
+    /// The next button event broadcast by this elevator which the task manager has not handled yet.
+    pub fn next_local_event(&mut self) -> Option<ButtonEvent> {
+        self.bus.next_local()
+    }
+
+    /// Opens an envelope received from the network, see `MessageBus::receive`.
+    pub fn receive(&mut self, envelope: &Envelope) -> Result<Option<Message>, ProtocolError> {
+        self.bus.receive(envelope)
+    }
+
+    /// The `Taken` events of the orders in the queue.
+    pub fn taken_orders(&self) -> &[ButtonEvent] {
+        &self.queued
     }
 
     pub fn get_order_list(&self) -> VecDeque<Order> {
@@ -292,17 +702,67 @@ impl ElevController {
         order_queue
     }
 
-    pub fn set_button_light_for_order(&mut self, action: &ButtonType, floor: Floor, light: Light) {
+    pub fn set_button_light_for_order(&mut self, action: &ButtonType, floor: Floor, light: Light) -> ElevResult<()> {
         match action {
             ButtonType::CabCall =>{
-                self.driver.set_button_light(Button::Internal(floor), light).unwrap();
+                self.driver.set_button_light(Button::Internal(floor), light)?;
             }
             ButtonType::HallUpCall =>{
-                self.driver.set_button_light(Button::CallUp(floor), light).unwrap();
+                self.driver.set_button_light(Button::CallUp(floor), light)?;
             }
             ButtonType::HallDownCall =>{
-                self.driver.set_button_light(Button::CallDown(floor), light).unwrap();
+                self.driver.set_button_light(Button::CallDown(floor), light)?;
+            }
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::clock::VirtualClock;
+    use crate::elev_simulator::{SimElevator, DEFAULT_TRAVEL_TIME};
+
+    struct NullTransmitter;
+
+    impl MessageTransmitter for NullTransmitter {
+        fn transmit(&self, _message: &Message) -> io::Result<u64> {
+            Ok(0)
+        }
+    }
+
+    /// Serves the orders in the order they are given and returns the floors the door opened at.
+    fn door_openings(service_mode: ServiceMode, orders: &[Order]) -> Vec<isize> {
+        let clock = VirtualClock::new(SystemTime::UNIX_EPOCH);
+        let shared_clock: SharedClock = Arc::new(clock.clone());
+        let car = SimElevator::new(4, DEFAULT_TRAVEL_TIME, shared_clock.clone());
+        let mut controller = ElevController::new(1, Box::new(NullTransmitter), car, shared_clock).unwrap();
+        controller.set_building(Building{floors: 4, ..Building::default()});
+        controller.set_service_mode(service_mode);
+        for (sequence, order) in orders.iter().enumerate() {
+            let id = OrderId{origin: 1, sequence: sequence as u64};
+            controller.add_order(ButtonEvent{request: RequestType::Taken, order: order.clone(), origin: 1, id, version: 0, releases: 0, created_ms: 0});
+        }
+        let mut openings = vec![];
+        for _ in 0..6000 {
+            let was_open = controller.is_door_open();
+            controller.handle_order().unwrap();
+            if controller.is_door_open() && !was_open {
+                openings.push(controller.get_current_floor().unwrap());
             }
+            clock.advance(Duration::from_millis(10));
         }
+        assert!(controller.get_order_list().is_empty());
+        openings
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn collective_serves_orders_in_the_direction_of_travel_first() {
+        let orders = [Order{floor: 2, order_type: ButtonType::HallDownCall}, Order{floor: 3, order_type: ButtonType::CabCall},
+                      Order{floor: 1, order_type: ButtonType::HallUpCall}];
+        // Up to the cab call, stopping for the up call on the way, then down to the down call
+        assert_eq!(door_openings(ServiceMode::Collective, &orders), vec![1, 3, 2]);
+        assert_eq!(door_openings(ServiceMode::Fifo, &orders), vec![2, 3, 1]);
+    }
+}
@@ -0,0 +1,94 @@
+use elevator_driver::elev_driver::*;
+use std::io;
+
+/// The operations `ElevController` needs from an elevator car.
+/// Implemented by `TcpElevator` for the hardware server, other backends (simulators, trace players,
+/// other panels) only need to implement this trait to be driven by the controller.
+pub trait ElevHardware {
+    fn get_floor_signal(&self) -> io::Result<Floor>;
+    fn get_button_signal(&self, button: Button) -> io::Result<Signal>;
+    fn set_motor_dir(&self, dir: MotorDir) -> io::Result<()>;
+    fn set_all_light(&self, mode: Light) -> io::Result<()>;
+    fn set_button_light(&self, button: Button, mode: Light) -> io::Result<()>;
+    fn set_floor_light(&self, floor: Floor) -> io::Result<()>;
+    fn set_door_light(&self, mode: Light) -> io::Result<()>;
+    fn get_obstr_signal(&self) -> io::Result<Signal>;
+    fn get_stop_signal(&self) -> io::Result<Signal>;
+    fn set_stop_light(&self, mode: Light) -> io::Result<()>;
+
+    /// The hardware server has no door buttons, backends which have them override these.
+    fn get_door_open_signal(&self) -> io::Result<Signal> {
+        Ok(Signal::Low)
+    }
+
+    fn get_door_close_signal(&self) -> io::Result<Signal> {
+        Ok(Signal::Low)
+    }
+
+    /// Re-establishes the link to the hardware after a failure.
+    /// Backends without a link to lose have nothing to do.
+    fn reconnect(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `ElevIo` connected to an elevator hardware server over TCP, able to reconnect if the link is lost.
+pub struct TcpElevator {
+    ip: String,
+    port: u16,
+    io: ElevIo,
+}
+
+impl TcpElevator {
+    pub fn connect(ip: &str, port: u16) -> io::Result<Self> {
+        let io = ElevIo::new(ip, port)?;
+        Ok(TcpElevator{ip: ip.to_string(), port, io})
+    }
+}
+
+impl ElevHardware for TcpElevator {
+    fn get_floor_signal(&self) -> io::Result<Floor> {
+        self.io.get_floor_signal()
+    }
+
+    fn get_button_signal(&self, button: Button) -> io::Result<Signal> {
+        self.io.get_button_signal(button)
+    }
+
+    fn set_motor_dir(&self, dir: MotorDir) -> io::Result<()> {
+        self.io.set_motor_dir(dir)
+    }
+
+    fn set_all_light(&self, mode: Light) -> io::Result<()> {
+        self.io.set_all_light(mode)
+    }
+
+    fn set_button_light(&self, button: Button, mode: Light) -> io::Result<()> {
+        self.io.set_button_light(button, mode)
+    }
+
+    fn set_floor_light(&self, floor: Floor) -> io::Result<()> {
+        self.io.set_floor_light(floor)
+    }
+
+    fn set_door_light(&self, mode: Light) -> io::Result<()> {
+        self.io.set_door_light(mode)
+    }
+
+    fn get_obstr_signal(&self) -> io::Result<Signal> {
+        self.io.get_obstr_signal()
+    }
+
+    fn get_stop_signal(&self) -> io::Result<Signal> {
+        self.io.get_stop_signal()
+    }
+
+    fn set_stop_light(&self, mode: Light) -> io::Result<()> {
+        self.io.set_stop_light(mode)
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        self.io = ElevIo::new(&self.ip, self.port)?;
+        Ok(())
+    }
+}
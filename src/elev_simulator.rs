@@ -0,0 +1,374 @@
+use elevator_driver::elev_driver::*;
+use std::io;
+use std::io::BufRead;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use std::time::SystemTime;
+
+use crate::clock::SharedClock;
+use crate::elev_hardware::ElevHardware;
+
+/// Time the simulated car uses to travel from one floor to the next.
+pub const DEFAULT_TRAVEL_TIME: Duration = Duration::from_secs(2);
+/// Fraction of a floor around each floor where the floor sensor is active.
+const SENSOR_WIDTH: f64 = 0.05;
+
+const BUTTON_UP: usize = 0;
+const BUTTON_DOWN: usize = 1;
+const BUTTON_CAB: usize = 2;
+
+/// In-process simulated elevator car.
+/// The car moves with constant speed while the motor is running and stops at the top and
+/// bottom floor. Button presses are latched until the controller reads them once.
+/// Clones share the same car so a panel thread can press buttons while the controller runs.
+#[derive(Clone)]
+pub struct SimElevator {
+    state: Arc<Mutex<SimState>>,
+}
+
+struct SimState {
+    n_floors: u8,
+    travel_time: Duration,
+    position: f64,
+    motor_dir: f64,
+    last_update: SystemTime,
+    clock: SharedClock,
+    buttons: Vec<[bool; 3]>,
+    button_lights: Vec<[bool; 3]>,
+    floor_light: u8,
+    door_light: bool,
+    obstructed: bool,
+    door_open_button: bool,
+    door_close_button: bool,
+    stop_button: bool,
+    stop_light: bool,
+    /// The car does not move even if the motor runs.
+    jammed: bool,
+}
+
+fn button_index(button: &Button) -> (&Floor, usize) {
+    match button {
+        Button::CallUp(floor) => (floor, BUTTON_UP),
+        Button::CallDown(floor) => (floor, BUTTON_DOWN),
+        Button::Internal(floor) => (floor, BUTTON_CAB),
+    }
+}
+
+fn invalid_floor() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Floor out of range for simulated elevator")
+}
+
+impl SimState {
+    fn update_position(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.last_update).unwrap_or(Duration::from_secs(0));
+        self.last_update = now;
+        if self.jammed {
+            return;
+        }
+        let travelled = elapsed.as_secs_f64() / self.travel_time.as_secs_f64();
+        let top = (self.n_floors - 1) as f64;
+        self.position = (self.position + self.motor_dir * travelled).max(0.0).min(top);
+    }
+
+    fn floor(&self) -> Floor {
+        let nearest = self.position.round();
+        if (self.position - nearest).abs() <= SENSOR_WIDTH {
+            Floor::At(nearest as u8)
+        } else {
+            Floor::Between
+        }
+    }
+
+    fn slot(&self, button: &Button) -> io::Result<(usize, usize)> {
+        match button_index(button) {
+            (Floor::At(floor), kind) if *floor < self.n_floors => Ok((*floor as usize, kind)),
+            _ => Err(invalid_floor()),
+        }
+    }
+}
+
+impl SimElevator {
+    pub fn new(n_floors: u8, travel_time: Duration, clock: SharedClock) -> Self {
+        let state = SimState {
+            n_floors,
+            travel_time,
+            position: 0.0,
+            motor_dir: 0.0,
+            last_update: clock.now(),
+            clock,
+            buttons: vec![[false; 3]; n_floors as usize],
+            button_lights: vec![[false; 3]; n_floors as usize],
+            floor_light: 0,
+            door_light: false,
+            obstructed: false,
+            door_open_button: false,
+            door_close_button: false,
+            stop_button: false,
+            stop_light: false,
+            jammed: false,
+        };
+        SimElevator{state: Arc::new(Mutex::new(state))}
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SimState> {
+        // A panicking panel thread must not take the car down with it
+        match self.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Presses a button on the simulated panel. The press is held until the controller reads it.
+    pub fn press_button(&self, button: Button) -> io::Result<()> {
+        let mut state = self.lock();
+        let (floor, kind) = state.slot(&button)?;
+        state.buttons[floor][kind] = true;
+        Ok(())
+    }
+
+    /// Sets the obstruction switch, it stays in that position until set again.
+    pub fn set_obstructed(&self, obstructed: bool) {
+        self.lock().obstructed = obstructed;
+    }
+
+    /// Presses the door open or door close button, held until the controller reads it.
+    pub fn press_door_button(&self, open: bool) {
+        let mut state = self.lock();
+        if open {
+            state.door_open_button = true;
+        } else {
+            state.door_close_button = true;
+        }
+    }
+
+    /// Presses the stop button, held until the controller reads it.
+    pub fn press_stop_button(&self) {
+        self.lock().stop_button = true;
+    }
+
+    /// Jams the car so it stays where it is even while the motor runs, or frees it again.
+    pub fn set_jammed(&self, jammed: bool) {
+        let mut state = self.lock();
+        state.update_position();
+        state.jammed = jammed;
+    }
+
+    /// Describes the car position and every lamp on the panel.
+    pub fn panel_status(&self) -> String {
+        let mut state = self.lock();
+        state.update_position();
+        let mut status = format!("position {:.2} floor lamp {} door {}{}", state.position, state.floor_light, if state.door_light {"open"} else {"closed"},
+                                 if state.obstructed {" obstructed"} else {""});
+        if state.stop_light {
+            status.push_str(" stop lamp on");
+        }
+        if state.jammed {
+            status.push_str(" jammed");
+        }
+        for (floor, lights) in state.button_lights.iter().enumerate() {
+            status.push_str(&format!("\n  floor {}: up {} down {} cab {}", floor, lights[BUTTON_UP] as u8, lights[BUTTON_DOWN] as u8, lights[BUTTON_CAB] as u8));
+        }
+        status
+    }
+
+    /// Whether the lamp of a button is lit, false for a button on no floor of the car.
+    pub fn is_button_lit(&self, button: Button) -> bool {
+        let state = self.lock();
+        match state.slot(&button) {
+            Ok((floor, kind)) => state.button_lights[floor][kind],
+            Err(_) => false,
+        }
+    }
+
+    /// Reads panel commands from stdin until it is closed.
+    /// Commands are `cab <floor>`, `up <floor>`, `down <floor>`, `open`, `close`,
+    /// `obstruct on|off`, `jam on|off`, `stop` and `status`.
+    pub fn run_stdin_panel(self) {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["status"] => {
+                    println!("[elev_simulator] {}", self.panel_status());
+                    continue;
+                }
+                ["open"] => {
+                    self.press_door_button(true);
+                    continue;
+                }
+                ["close"] => {
+                    self.press_door_button(false);
+                    continue;
+                }
+                ["stop"] => {
+                    self.press_stop_button();
+                    continue;
+                }
+                ["obstruct", "on"] => {
+                    self.set_obstructed(true);
+                    continue;
+                }
+                ["obstruct", "off"] => {
+                    self.set_obstructed(false);
+                    continue;
+                }
+                ["jam", "on"] => {
+                    self.set_jammed(true);
+                    continue;
+                }
+                ["jam", "off"] => {
+                    self.set_jammed(false);
+                    continue;
+                }
+                [_, _] => {}
+                _ => {
+                    println!("[elev_simulator] Usage: cab|up|down <floor>, open, close, obstruct on|off, jam on|off, stop or status");
+                    continue;
+                }
+            }
+            let floor = match words[1].parse::<u8>() {
+                Ok(floor) => Floor::At(floor),
+                Err(_) => {
+                    println!("[elev_simulator] Invalid floor {:?}", words[1]);
+                    continue;
+                }
+            };
+            let button = match words[0] {
+                "cab" => Button::Internal(floor),
+                "up" => Button::CallUp(floor),
+                "down" => Button::CallDown(floor),
+                _ => {
+                    println!("[elev_simulator] Unknown button {:?}", words[0]);
+                    continue;
+                }
+            };
+            if let Err(e) = self.press_button(button) {
+                println!("[elev_simulator] {}", e);
+            }
+        }
+    }
+}
+
+impl ElevHardware for SimElevator {
+    fn get_floor_signal(&self) -> io::Result<Floor> {
+        let mut state = self.lock();
+        state.update_position();
+        Ok(state.floor())
+    }
+
+    fn get_button_signal(&self, button: Button) -> io::Result<Signal> {
+        let mut state = self.lock();
+        let (floor, kind) = state.slot(&button)?;
+        if state.buttons[floor][kind] {
+            state.buttons[floor][kind] = false;
+            Ok(Signal::High)
+        } else {
+            Ok(Signal::Low)
+        }
+    }
+
+    fn set_motor_dir(&self, dir: MotorDir) -> io::Result<()> {
+        let mut state = self.lock();
+        state.update_position();
+        state.motor_dir = match dir {
+            MotorDir::Up => 1.0,
+            MotorDir::Down => -1.0,
+            MotorDir::Stop => 0.0,
+        };
+        Ok(())
+    }
+
+    fn set_all_light(&self, mode: Light) -> io::Result<()> {
+        let on = match mode {
+            Light::On => true,
+            Light::Off => false,
+        };
+        let mut state = self.lock();
+        for lights in state.button_lights.iter_mut() {
+            *lights = [on; 3];
+        }
+        state.door_light = on;
+        Ok(())
+    }
+
+    fn set_button_light(&self, button: Button, mode: Light) -> io::Result<()> {
+        let mut state = self.lock();
+        let (floor, kind) = state.slot(&button)?;
+        state.button_lights[floor][kind] = match mode {
+            Light::On => true,
+            Light::Off => false,
+        };
+        Ok(())
+    }
+
+    fn set_floor_light(&self, floor: Floor) -> io::Result<()> {
+        let mut state = self.lock();
+        match floor {
+            Floor::At(num) if num < state.n_floors => {
+                state.floor_light = num;
+                Ok(())
+            }
+            _ => Err(invalid_floor()),
+        }
+    }
+
+    fn set_door_light(&self, mode: Light) -> io::Result<()> {
+        let mut state = self.lock();
+        state.door_light = match mode {
+            Light::On => true,
+            Light::Off => false,
+        };
+        Ok(())
+    }
+
+    fn get_obstr_signal(&self) -> io::Result<Signal> {
+        if self.lock().obstructed {
+            Ok(Signal::High)
+        } else {
+            Ok(Signal::Low)
+        }
+    }
+
+    fn get_door_open_signal(&self) -> io::Result<Signal> {
+        let mut state = self.lock();
+        if state.door_open_button {
+            state.door_open_button = false;
+            Ok(Signal::High)
+        } else {
+            Ok(Signal::Low)
+        }
+    }
+
+    fn get_door_close_signal(&self) -> io::Result<Signal> {
+        let mut state = self.lock();
+        if state.door_close_button {
+            state.door_close_button = false;
+            Ok(Signal::High)
+        } else {
+            Ok(Signal::Low)
+        }
+    }
+
+    fn get_stop_signal(&self) -> io::Result<Signal> {
+        let mut state = self.lock();
+        if state.stop_button {
+            state.stop_button = false;
+            Ok(Signal::High)
+        } else {
+            Ok(Signal::Low)
+        }
+    }
+
+    fn set_stop_light(&self, mode: Light) -> io::Result<()> {
+        self.lock().stop_light = match mode {
+            Light::On => true,
+            Light::Off => false,
+        };
+        Ok(())
+    }
+}
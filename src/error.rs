@@ -0,0 +1,66 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::SystemTimeError;
+
+/// Failures in the control loop, each with a policy for how the loop recovers from it.
+#[derive(Debug)]
+pub enum ElevError {
+    /// Communication with the elevator hardware failed.
+    Driver(io::Error),
+    /// Sending to or receiving from the other elevators failed.
+    Network(io::Error),
+    /// The system clock stepped backwards.
+    Clock(SystemTimeError),
+    /// The thread receiving packets from the other elevators has stopped, nothing more will be heard from them.
+    Receiver,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RecoveryPolicy {
+    /// Stop driving the car and reconnect to the elevator hardware.
+    Reconnect,
+    /// Drop the rest of this iteration of the control loop and try again on the next one.
+    SkipTick,
+    /// Stop the car and shut down.
+    SafeStop,
+}
+
+pub type ElevResult<T> = Result<T, ElevError>;
+
+impl ElevError {
+    pub fn policy(&self) -> RecoveryPolicy {
+        match self {
+            ElevError::Driver(_) => RecoveryPolicy::Reconnect,
+            ElevError::Network(_) => RecoveryPolicy::SkipTick,
+            ElevError::Clock(_) => RecoveryPolicy::SkipTick,
+            // Restarting is the way to get a new socket, the journal keeps the orders meanwhile
+            ElevError::Receiver => RecoveryPolicy::SafeStop,
+        }
+    }
+}
+
+impl fmt::Display for ElevError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElevError::Driver(e) => write!(f, "Elevator hardware error: {}", e),
+            ElevError::Network(e) => write!(f, "Network error: {}", e),
+            ElevError::Clock(e) => write!(f, "System clock error: {}", e),
+            ElevError::Receiver => write!(f, "Network receiver stopped"),
+        }
+    }
+}
+
+impl Error for ElevError {}
+
+impl From<io::Error> for ElevError {
+    fn from(error: io::Error) -> Self {
+        ElevError::Driver(error)
+    }
+}
+
+impl From<SystemTimeError> for ElevError {
+    fn from(error: SystemTimeError) -> Self {
+        ElevError::Clock(error)
+    }
+}
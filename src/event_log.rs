@@ -0,0 +1,150 @@
+use log::error;
+use serde::*;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::SystemTime;
+
+use crate::clock::SharedClock;
+use crate::config::EventLogConfig;
+use crate::elev_controller::{ButtonEvent, Order, OrderId};
+use crate::task_manager::States;
+
+/// Something that happened to an order, one line in the event log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    /// Milliseconds since the unix epoch on the clock of the elevator.
+    pub time_ms: u64,
+    pub elevator_id: u32,
+    pub kind: EventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// Broadcast by this elevator.
+    Sent(ButtonEvent),
+    /// Received from another elevator.
+    Received(ButtonEvent),
+    /// A task in the task manager changed state, `id` is the press the task serves.
+    Transition{order: Order, origin: u32, id: OrderId, from: States, to: States},
+}
+
+/// Where recorded events end up.
+pub trait EventSink {
+    fn write(&mut self, event: &Event) -> io::Result<()>;
+}
+
+/// Hands the events to another part of the program, used when replaying.
+impl EventSink for Sender<Event> {
+    fn write(&mut self, event: &Event) -> io::Result<()> {
+        self.send(event.clone()).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "event receiver is gone"))
+    }
+}
+
+/// Events as JSON lines in a file. A file grown past its size limit is renamed to `path.1`,
+/// older files move on to `path.2` and so on, and the oldest is removed.
+pub struct EventLog {
+    path: PathBuf,
+    max_file_bytes: u64,
+    rotated_files: u32,
+    file: Option<fs::File>,
+    size: u64,
+}
+
+impl EventLog {
+    pub fn new(path: PathBuf, config: &EventLogConfig) -> Self {
+        EventLog{path, max_file_bytes: config.max_file_bytes, rotated_files: config.rotated_files, file: None, size: 0}
+    }
+
+    /// Path of the `index`th rotated file, 0 being the file written to.
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        match index {
+            0 => self.path.clone(),
+            _ => PathBuf::from(format!("{}.{}", self.path.display(), index)),
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        if self.rotated_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for index in (0..self.rotated_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads every event in a log file, skipping lines which can not be parsed.
+    pub fn read(path: &Path) -> io::Result<Vec<Event>> {
+        let file = fs::File::open(path)?;
+        let mut events = vec![];
+        for line in BufReader::new(file).lines() {
+            if let Ok(event) = serde_json::from_str(&line?) {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl EventSink for EventLog {
+    fn write(&mut self, event: &Event) -> io::Result<()> {
+        let mut line = serde_json::to_vec(event).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        line.push(b'\n');
+        if self.file.is_some() && self.size + line.len() as u64 > self.max_file_bytes {
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            let file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(&line)?;
+        }
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Stamps events with the time and the elevator id and writes them to the sink, if there is one.
+pub struct EventRecorder {
+    elevator_id: u32,
+    clock: SharedClock,
+    sink: Option<Box<dyn EventSink>>,
+}
+
+impl EventRecorder {
+    pub fn new(elevator_id: u32, clock: SharedClock) -> Self {
+        EventRecorder{elevator_id, clock, sink: None}
+    }
+
+    pub fn set_sink(&mut self, sink: Box<dyn EventSink>) {
+        self.sink = Some(sink);
+    }
+
+    pub fn elevator_id(&self) -> u32 {
+        self.elevator_id
+    }
+
+    /// Records an event. A sink that fails is dropped, so a full disk does not stop the elevator.
+    pub fn record(&mut self, kind: EventKind) {
+        let sink = match self.sink.as_mut() {
+            Some(sink) => sink,
+            None => return,
+        };
+        let time_ms = self.clock.now().duration_since(SystemTime::UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or(0);
+        let event = Event{time_ms, elevator_id: self.elevator_id, kind};
+        if let Err(e) = sink.write(&event) {
+            error!("Unable to write event log, no more events are recorded: {}", e);
+            self.sink = None;
+        }
+    }
+}
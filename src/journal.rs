@@ -0,0 +1,65 @@
+use serde::*;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::elev_controller::{ButtonEvent, Order};
+
+/// Orders this elevator must not forget if the process restarts.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct JournalEntry {
+    /// Cab calls made in this elevator which are not yet served.
+    pub cab_calls: Vec<Order>,
+    /// The `Taken` events of the hall calls this elevator has taken and not yet served,
+    /// so they are restored as taken by this elevator for the same presses.
+    #[serde(default)]
+    pub taken_hall_calls: Vec<ButtonEvent>,
+}
+
+/// On-disk copy of the pending orders, rewritten atomically whenever they change.
+pub struct Journal {
+    path: PathBuf,
+    written: JournalEntry,
+}
+
+impl Journal {
+    pub fn new(path: PathBuf) -> Self {
+        Journal{path, written: JournalEntry::default()}
+    }
+
+    /// Reads the orders left by the previous run, a missing journal means there are none.
+    pub fn load(&mut self) -> io::Result<JournalEntry> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(JournalEntry::default()),
+            Err(e) => return Err(e),
+        };
+        let entry: JournalEntry = serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.written = entry.clone();
+        Ok(entry)
+    }
+
+    /// Writes the entry if it differs from what is on disk.
+    /// The journal is written to a temporary file and renamed over the old one,
+    /// so a crash while writing leaves either the old or the new journal behind. The directory is
+    /// synced after the rename, otherwise the rename itself may be lost on power failure.
+    pub fn record(&mut self, entry: JournalEntry) -> io::Result<()> {
+        if entry == self.written {
+            return Ok(());
+        }
+        let data = serde_json::to_vec(&entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::File::open(dir)?.sync_all()?;
+        self.written = entry;
+        Ok(())
+    }
+}
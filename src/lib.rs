@@ -0,0 +1,26 @@
+//! Controller for one elevator cooperating with other elevators over UDP broadcast.
+//! The `elevator-project` binary runs it, `replay` replays its event log.
+
+pub mod task_manager;
+pub mod elev_controller;
+pub mod elev_hardware;
+pub mod elev_simulator;
+pub mod clock;
+pub mod network;
+pub mod sim_harness;
+pub mod error;
+pub mod journal;
+pub mod protocol;
+pub mod peers;
+pub mod assignment;
+pub mod cost_function;
+pub mod config;
+pub mod building;
+pub mod cli;
+pub mod logger;
+pub mod event_log;
+pub mod metrics;
+pub mod delivery;
+pub mod digest;
+pub mod order_state;
+pub mod message_bus;
@@ -0,0 +1,181 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use log::kv::{Key, Value, VisitSource};
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// Module path of `main.rs`, every other target of this crate starts with it.
+const CRATE_ROOT: &str = "elevator_project";
+
+/// Level to log at, optionally different per module, written like `info,task_manager=debug`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    pub fn new(default: LevelFilter) -> Self {
+        LogFilter{default, modules: vec![]}
+    }
+
+    /// Level of the most specific module setting matching the module, or the default.
+    pub fn level_for(&self, module: &str) -> LevelFilter {
+        self.modules.iter()
+            .filter(|(name, _)| module == name || module.starts_with(&format!("{}::", name)))
+            .max_by_key(|(name, _)| name.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// Most verbose level any module logs at.
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, std::cmp::max)
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let parse_level = |level: &str| level.parse::<LevelFilter>()
+            .map_err(|_| format!("unknown level {:?}, expected off, error, warn, info, debug or trace", level));
+        let mut filter = LogFilter::new(LevelFilter::Info);
+        for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            match part.split_once('=') {
+                Some((module, level)) if !module.is_empty() => filter.modules.push((module.to_string(), parse_level(level)?)),
+                Some(_) => return Err(format!("module name missing in {:?}", part)),
+                None => filter.default = parse_level(part)?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.default.to_string().to_lowercase())?;
+        for (module, level) in &self.modules {
+            write!(f, ",{}={}", module, level.to_string().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// One readable line per record, `key=value` fields after the message.
+    Text,
+    /// One JSON object per line, fields as members.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected text or json".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Collects the key-value fields of a record, numbers and booleans stay numbers and booleans in JSON.
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = if let Some(number) = value.to_u64() {
+            serde_json::json!(number)
+        } else if let Some(number) = value.to_i64() {
+            serde_json::json!(number)
+        } else if let Some(boolean) = value.to_bool() {
+            serde_json::json!(boolean)
+        } else {
+            serde_json::json!(value.to_string())
+        };
+        self.0.push((key.to_string(), value));
+        Ok(())
+    }
+}
+
+struct Logger {
+    filter: LogFilter,
+    format: LogFormat,
+}
+
+/// Module of this crate a record comes from, `task_manager` for `elevator_project::task_manager`
+/// and `main` for the crate root.
+fn module_of(target: &str) -> &str {
+    if target == CRATE_ROOT {
+        return "main";
+    }
+    target.strip_prefix(CRATE_ROOT).and_then(|module| module.strip_prefix("::")).unwrap_or(target)
+}
+
+impl Logger {
+    fn format(&self, record: &Record, fields: Fields) -> String {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let module = module_of(record.target());
+        match self.format {
+            LogFormat::Text => {
+                let mut line = format!("{}.{:03} {:<5} [{}] {}", timestamp.as_secs(), timestamp.subsec_millis(), record.level(), module, record.args());
+                for (key, value) in fields.0 {
+                    match value {
+                        serde_json::Value::String(text) => line.push_str(&format!(" {}={}", key, text)),
+                        value => line.push_str(&format!(" {}={}", key, value)),
+                    }
+                }
+                line
+            }
+            LogFormat::Json => {
+                let mut object = serde_json::Map::new();
+                object.insert("time".to_string(), serde_json::json!(timestamp.as_millis() as u64));
+                object.insert("level".to_string(), serde_json::json!(record.level().as_str()));
+                object.insert("module".to_string(), serde_json::json!(module));
+                object.insert("message".to_string(), serde_json::json!(record.args().to_string()));
+                for (key, value) in fields.0 {
+                    object.insert(key, value);
+                }
+                serde_json::Value::Object(object).to_string()
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level_for(module_of(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut fields = Fields(vec![]);
+        // Fields only fail to visit if the visitor does, which ours never does
+        let _ = record.key_values().visit(&mut fields);
+        println!("{}", self.format(record, fields));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger behind the `log` macros, only the first call has an effect.
+pub fn init(filter: LogFilter, format: LogFormat) {
+    let max_level = filter.max_level();
+    if log::set_boxed_logger(Box::new(Logger{filter, format})).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
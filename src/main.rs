@@ -1,98 +1,231 @@
-use elevator_driver::*;
+use log::{error, info, warn};
 use std::thread;
+use std::time::Duration;
 use std::sync::mpsc::*;
+use std::sync::Arc;
 use std::env;
-use regex::Regex;
+use std::path::{Path, PathBuf};
 
-mod task_manager;
-mod elev_controller;
+use elevator_project::{assignment, cli, clock, config, elev_controller, elev_hardware, elev_simulator,
+                       error, event_log, journal, logger, metrics, network, protocol, sim_harness, task_manager};
 
-fn main() {
-    println!("Starting Elevator server for {} floors", elev_driver::N_FLOORS);
-    let args: Vec<String> = env::args().collect();
-    let re = Regex::new(r"((?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)))+$").unwrap();
-    let ip_addr = network_rust::localip::get_localip().unwrap().to_string();
-    let lower_ip_part = re.find(&ip_addr).unwrap().as_str();
+/// Configuration file read when no --config is given, if it exists.
+const DEFAULT_CONFIG_PATH: &str = "elevator.toml";
 
-    let mut id = lower_ip_part.parse::<u32>().unwrap();
-    let mut udp_broadcast_port = elev_controller::BCAST_PORT;
-    let mut elevator_ip = elev_driver::DEFAULT_IP_ADDRESS;
-    let mut elevator_port = elev_driver::DEFAULT_PORT;
-    match args.len() {
-        1 => {}
-        2 => {
-            let cmd = &args[1];
-            if cmd.contains("--help") {
-                println!("elevator-project (elevator id) (udp_broadcast_port) (elevator hardware ip) (elevator hardware port)");
-                std::process::exit(0);
-            }
-            id = cmd.parse::<u32>().unwrap();
-        }
-        3 => {
-            id = (&args[1]).parse::<u32>().unwrap();
-            udp_broadcast_port = (&args[2]).parse::<u16>().unwrap();
-        }
-        4 => {
-            id = (&args[1]).parse::<u32>().unwrap();
-            udp_broadcast_port = (&args[2]).parse::<u16>().unwrap();
-            elevator_ip = &args[3];
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cli = match cli::Cli::parse(&args, |name| env::var(name).ok()) {
+        Ok(cli) => cli,
+        Err(e) => {
+            println!("[main] {}", e);
+            std::process::exit(2);
         }
-        5 => {
-            id = (&args[1]).parse::<u32>().unwrap();
-            udp_broadcast_port = (&args[2]).parse::<u16>().unwrap();
-            elevator_ip = &args[3];
-            elevator_port = (&args[4]).parse::<u16>().unwrap();
+    };
+    if cli.help {
+        println!("{}", cli::HELP);
+        std::process::exit(0);
+    }
+    logger::init(cli.log_level.clone(), cli.log_format);
+    // --shared-assignment assigns hall calls from the shared peer state instead of only racing timers
+    let assignment_mode = if cli.shared_assignment {
+        assignment::AssignmentMode::Shared
+    } else {
+        assignment::AssignmentMode::TimerRace
+    };
+    // --fifo serves orders in the order they were taken instead of collecting them on the way
+    let service_mode = if cli.fifo {
+        elev_controller::ServiceMode::Fifo
+    } else {
+        elev_controller::ServiceMode::Collective
+    };
+    let mut config = load_config(cli.config.clone());
+    if let Some(floors) = cli.floors {
+        config.building.floors = floors;
+        if let Err(e) = config.validate() {
+            error!("invalid value \"{}\" for --floors: {}", floors, e);
+            std::process::exit(2);
         }
-        _ => {
-            println!("Invalid number of arguments!");
-            println!("elevator-project (elevator id) (udp_broadcast_port) (elevator hardware ip) (elevator hardware port)");
-            std::process::exit(0);
+    }
+    info!("Starting Elevator server for {} floors, log level {}", config.building.floors, cli.log_level);
+    if let Some(kind) = cli.cost_function {
+        config.cost.function = kind;
+    }
+    info!("Using the {} cost function", config.cost.function);
+    if let Some(scenario) = &cli.harness {
+        run_harness(scenario, cli.seed, assignment_mode, service_mode, &config);
+        std::process::exit(0);
+    }
+    if let Some(port) = cli.port {
+        config.network.udp_broadcast_port = port;
+    }
+    if let Some(hw_addr) = &cli.hw_addr {
+        config.hardware.ip = hw_addr.ip.clone();
+        if let Some(port) = hw_addr.port {
+            config.hardware.port = port;
         }
     }
-  
-    let (network_sender, network_reciver) = channel::<elev_controller::ButtonEvent>();
-    let (internal_sender, internal_reciver) = channel::<elev_controller::ButtonEvent>();
+    let id = match cli.id {
+        Some(id) => id,
+        None => match id_from_local_ip() {
+            Some(id) => id,
+            None => {
+                error!("Unable to derive an elevator id from the local IP address, give one with --id");
+                std::process::exit(2);
+            }
+        },
+    };
+    let udp_broadcast_port = config.network.udp_broadcast_port;
+    info!("Elevator {} on UDP port {}", id, udp_broadcast_port);
+
+    let (network_sender, network_reciver) = channel::<protocol::Envelope>();
+    let socket = match network_rust::bcast::BcastReceiver::new(udp_broadcast_port) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("{}", error::ElevError::Network(e));
+            std::process::exit(1);
+        }
+    };
     thread::spawn(move || {
-        let socket = network_rust::bcast::BcastReceiver::new(udp_broadcast_port).unwrap();
         socket.run(network_sender);
     });
-    let mut taskmanager = task_manager::TaskManager::new(internal_sender, id, udp_broadcast_port, elevator_ip, elevator_port).unwrap();
-    
+    let system_clock: clock::SharedClock = Arc::new(clock::SystemClock);
+    let metrics = metrics::Metrics::shared();
+    if config.metrics.enabled {
+        // The elevator is more important than its metrics, so it runs without them if the address is taken
+        if let Err(e) = metrics::serve(&config.metrics.address, metrics.clone()) {
+            warn!("Unable to serve metrics on {}: {}", config.metrics.address, e);
+        }
+    }
+    let transmitter = Box::new(network::UdpTransmitter::new(&config.network, id, system_clock.clone(), metrics.clone()));
+    let journal = journal::Journal::new(PathBuf::from(format!("elevator_{}.journal", id)));
+    let event_log = if config.event_log.enabled {
+        Some(event_log::EventLog::new(PathBuf::from(format!("elevator_{}.events", id)), &config.event_log))
+    } else {
+        None
+    };
+    if cli.sim {
+        let sim_elevator = elev_simulator::SimElevator::new(config.building.floors, elev_simulator::DEFAULT_TRAVEL_TIME, system_clock.clone());
+        let panel = sim_elevator.clone();
+        thread::spawn(move || {
+            panel.run_stdin_panel();
+        });
+        info!("Running simulated elevator, press buttons with cab|up|down <floor> or show lamps with status");
+        let taskmanager = task_manager::TaskManager::new(id, transmitter, sim_elevator, system_clock, Some(journal));
+        run(taskmanager, assignment_mode, service_mode, &config, event_log, metrics, network_reciver);
+    } else {
+        let elev_driver = connect_to_hardware(&config.hardware.ip, config.hardware.port);
+        let taskmanager = task_manager::TaskManager::new(id, transmitter, elev_driver, system_clock, Some(journal));
+        run(taskmanager, assignment_mode, service_mode, &config, event_log, metrics, network_reciver);
+    }
+}
+
+fn run<H: elev_hardware::ElevHardware>(taskmanager: error::ElevResult<task_manager::TaskManager<H>>, assignment_mode: assignment::AssignmentMode, service_mode: elev_controller::ServiceMode, config: &config::Config, event_log: Option<event_log::EventLog>, metrics: metrics::SharedMetrics, network_reciver: Receiver<protocol::Envelope>) {
+    let mut taskmanager = match taskmanager {
+        Ok(taskmanager) => taskmanager,
+        Err(e) => {
+            error!("Failed to start: {}", e);
+            std::process::exit(1);
+        }
+    };
+    taskmanager.set_assignment_mode(assignment_mode);
+    taskmanager.set_service_mode(service_mode);
+    taskmanager.set_config(config);
+    if let Some(event_log) = event_log {
+        taskmanager.set_event_sink(Box::new(event_log));
+    }
+    taskmanager.set_metrics(metrics);
     loop {
-        loop {
-            match network_reciver.try_recv() {
-                Ok(data) => {
-                    handle_network_message(&mut taskmanager, data);
+        let result = receive_envelopes(&mut taskmanager, &network_reciver).and_then(|()| {
+            taskmanager.deliver_local_events();
+            taskmanager.run_state_machine()
+        });
+        if let Err(e) = result {
+            match e.policy() {
+                error::RecoveryPolicy::Reconnect => {
+                    warn!("{}, reconnecting", e);
+                    taskmanager.hardware_lost();
                 }
-                Err(_) => {
-                    break
+                error::RecoveryPolicy::SkipTick => {
+                    warn!("{}, skipping tick", e);
+                }
+                error::RecoveryPolicy::SafeStop => {
+                    error!("{}, stopping elevator", e);
+                    taskmanager.safe_stop();
+                    std::process::exit(1);
                 }
             }
         }
-        loop {
-            match internal_reciver.try_recv() {
-                Ok(data) => {
-                    handle_network_message(&mut taskmanager, data);
-                }
-                Err(_) => {
-                    break
-                }
+    }
+}
+
+/// Hands every packet received since the last call to the task manager.
+fn receive_envelopes<H: elev_hardware::ElevHardware>(taskmanager: &mut task_manager::TaskManager<H>, network_reciver: &Receiver<protocol::Envelope>) -> error::ElevResult<()> {
+    loop {
+        match network_reciver.try_recv() {
+            Ok(envelope) => {
+                taskmanager.handle_envelope(envelope);
+            }
+            Err(TryRecvError::Empty) => {
+                return Ok(());
+            }
+            Err(TryRecvError::Disconnected) => {
+                return Err(error::ElevError::Receiver);
             }
         }
-        taskmanager.run_state_machine();
     }
 }
 
-fn handle_network_message(task_mgr: &mut task_manager::TaskManager, msg: elev_controller::ButtonEvent) {
-    match msg.request {
-        elev_controller::RequestType::Request => {
-            task_mgr.add_new_task(msg.order, msg.origin);
+/// Reads the configuration file given with --config, or the default one if it exists.
+/// An unreadable or invalid configuration stops the program instead of running with surprising settings.
+fn load_config(path: Option<PathBuf>) -> config::Config {
+    let path = match path {
+        Some(path) => path,
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => PathBuf::from(DEFAULT_CONFIG_PATH),
+        None => return config::Config::default(),
+    };
+    match config::Config::load(&path) {
+        Ok(config) => {
+            info!("Using configuration from {}", path.display());
+            config
         }
-        elev_controller::RequestType::Taken => {
-            task_mgr.set_task_taken(msg.order, msg.origin);
+        Err(e) => {
+            error!("{}: {}", path.display(), e);
+            std::process::exit(1);
         }
-        elev_controller::RequestType::Complete => {
-            task_mgr.set_task_complete(msg.order, msg.origin);
+    }
+}
+
+/// Connects to the elevator hardware server, waiting for it to come up if necessary.
+fn connect_to_hardware(elevator_ip: &str, elevator_port: u16) -> elev_hardware::TcpElevator {
+    let mut backoff = Duration::from_millis(500);
+    loop {
+        match elev_hardware::TcpElevator::connect(elevator_ip, elevator_port) {
+            Ok(elevator) => return elevator,
+            Err(e) => {
+                warn!("Connecting to elevator at {}:{} failed: {}, retrying in {:?}", elevator_ip, elevator_port, e, backoff);
+                thread::sleep(backoff);
+                backoff = std::cmp::min(backoff * 2, Duration::from_secs(10));
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+fn run_harness(name: &str, seed: u64, assignment_mode: assignment::AssignmentMode, service_mode: elev_controller::ServiceMode, config: &config::Config) {
+    let scenario = match name {
+        "packet-loss" => sim_harness::Scenario::packet_loss(seed),
+        "network-block" => sim_harness::Scenario::network_block(seed),
+        _ => {
+            error!("invalid value {:?} for --harness: expected packet-loss or network-block", name);
+            std::process::exit(2);
+        }
+    };
+    let scenario = sim_harness::Scenario{assignment_mode, service_mode, config: config.clone(), ..scenario};
+    let results = sim_harness::run(&scenario);
+    sim_harness::print_report(&results);
+}
+
+/// Default id of the elevator, the last number of the local IPv4 address.
+fn id_from_local_ip() -> Option<u32> {
+    let ip_addr = network_rust::localip::get_localip().ok()?.to_string();
+    ip_addr.rsplit('.').next()?.parse::<u32>().ok()
+}
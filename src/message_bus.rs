@@ -0,0 +1,202 @@
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::io;
+
+use crate::elev_controller::ButtonEvent;
+use crate::metrics::{Metrics, SharedMetrics};
+use crate::network::MessageTransmitter;
+use crate::protocol::{Envelope, Message, ProtocolError};
+
+/// Number of envelopes received, and sent, remembered to recognise further copies and echoes of them.
+/// The copies of a request arrive within a few retransmit intervals, far fewer messages than this are sent or received meanwhile.
+const RECENT_ENVELOPES: usize = 1024;
+
+/// Carries the messages of one elevator. A button event published by the elevator is delivered
+/// to it once, straight away, and broadcast to the others. The broadcast comes back from the
+/// network like every other, so envelopes this elevator sent are dropped, as are further copies
+/// of envelopes already delivered.
+pub struct MessageBus {
+    elevator_id: u32,
+    transmitter: Box<dyn MessageTransmitter>,
+    /// Events published by this elevator and not yet delivered to it.
+    local: VecDeque<ButtonEvent>,
+    /// Sender and message id of the envelopes delivered last, oldest first.
+    recent: VecDeque<(u32, u64)>,
+    /// Message id of the envelopes sent last, oldest first.
+    sent: VecDeque<u64>,
+    /// Envelopes received with the id of this elevator as sender which it never sent.
+    impostors: u64,
+    metrics: SharedMetrics,
+}
+
+impl MessageBus {
+    pub fn new(elevator_id: u32, transmitter: Box<dyn MessageTransmitter>) -> Self {
+        MessageBus{elevator_id, transmitter, local: VecDeque::new(), recent: VecDeque::new(), sent: VecDeque::new(),
+                   impostors: 0, metrics: Metrics::shared()}
+    }
+
+    pub fn set_metrics(&mut self, metrics: SharedMetrics) {
+        self.metrics = metrics;
+    }
+
+    /// Delivers a button event of this elevator to it and broadcasts it to the others.
+    /// The event is delivered even if the broadcast fails, so the elevator keeps serving its own buttons without a network.
+    pub fn publish(&mut self, event: ButtonEvent) -> io::Result<()> {
+        self.local.push_back(event.clone());
+        self.transmit(&Message::Button(event))
+    }
+
+    /// Broadcasts a message to the other elevators without delivering it to this one.
+    pub fn transmit(&mut self, message: &Message) -> io::Result<()> {
+        let message_id = self.transmitter.transmit(message)?;
+        remember(&mut self.sent, message_id);
+        Ok(())
+    }
+
+    /// The oldest event published by this elevator which has not been delivered to it yet.
+    pub fn next_local(&mut self) -> Option<ButtonEvent> {
+        self.local.pop_front()
+    }
+
+    /// Opens an envelope received from the network. Gives `None` for an echo of a broadcast of
+    /// this elevator and for a copy of an envelope already delivered, and counts them in the metrics.
+    /// An envelope with the id of this elevator which it never sent, from another elevator given the same id,
+    /// is dropped and counted too, with a warning the first time.
+    pub fn receive(&mut self, envelope: &Envelope) -> Result<Option<Message>, ProtocolError> {
+        let message = envelope.open()?;
+        if envelope.sender_id == self.elevator_id {
+            if self.sent.contains(&envelope.message_id) {
+                self.metrics.duplicate_suppressed("echo");
+            } else {
+                self.impostors += 1;
+                self.metrics.duplicate_suppressed("impostor");
+                if self.impostors == 1 {
+                    warn!("Received a packet this elevator did not send with its id {} as sender, is another elevator running with the same id?", self.elevator_id);
+                } else {
+                    debug!("Dropping another packet sent by an elevator with the id {} of this one", self.elevator_id);
+                }
+            }
+            return Ok(None);
+        }
+        if let Message::Button(event) = &message {
+            self.metrics.packet_received(&event.request);
+        }
+        let key = (envelope.sender_id, envelope.message_id);
+        if self.recent.contains(&key) {
+            self.metrics.duplicate_suppressed("copy");
+            return Ok(None);
+        }
+        remember(&mut self.recent, key);
+        Ok(Some(message))
+    }
+}
+
+fn remember<T>(recent: &mut VecDeque<T>, item: T) {
+    if recent.len() == RECENT_ENVELOPES {
+        recent.pop_front();
+    }
+    recent.push_back(item);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+    use crate::clock::{SharedClock, VirtualClock};
+    use crate::elev_controller::{ButtonType, Order, OrderId, RequestType};
+    use crate::protocol::Sealer;
+
+    const OWN_ID: u32 = 1;
+
+    /// Keeps the envelopes sent, so they can be received back like a broadcast, or fails every send.
+    struct FakeTransmitter {
+        sealer: Sealer,
+        sent: Arc<Mutex<Vec<Envelope>>>,
+        fail: bool,
+    }
+
+    impl MessageTransmitter for FakeTransmitter {
+        fn transmit(&self, message: &Message) -> io::Result<u64> {
+            if self.fail {
+                return Err(io::Error::from(io::ErrorKind::NotConnected));
+            }
+            let envelope = self.sealer.seal(message);
+            let message_id = envelope.message_id;
+            self.sent.lock().unwrap().push(envelope);
+            Ok(message_id)
+        }
+    }
+
+    fn clock() -> SharedClock {
+        Arc::new(VirtualClock::new(SystemTime::UNIX_EPOCH))
+    }
+
+    fn bus(fail: bool) -> (MessageBus, Arc<Mutex<Vec<Envelope>>>, SharedMetrics) {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let transmitter = FakeTransmitter{sealer: Sealer::new(OWN_ID, clock()), sent: sent.clone(), fail};
+        let mut bus = MessageBus::new(OWN_ID, Box::new(transmitter));
+        let metrics = Metrics::shared();
+        bus.set_metrics(metrics.clone());
+        (bus, sent, metrics)
+    }
+
+    fn event(origin: u32) -> ButtonEvent {
+        ButtonEvent{request: RequestType::Request, order: Order{floor: 2, order_type: ButtonType::HallUpCall}, origin,
+                    id: OrderId{origin, sequence: 1}, version: 0, releases: 0, created_ms: 0}
+    }
+
+    fn suppressed(metrics: &Metrics, reason: &str) -> u64 {
+        let prefix = format!("elevator_duplicates_suppressed_total{{reason=\"{}\"}} ", reason);
+        metrics.render().lines().find_map(|line| line.strip_prefix(prefix.as_str())).map_or(0, |count| count.parse().unwrap())
+    }
+
+    #[test]
+    fn published_event_is_delivered_once() {
+        let (mut bus, sent, metrics) = bus(false);
+        bus.publish(event(OWN_ID)).unwrap();
+        assert_eq!(bus.next_local(), Some(event(OWN_ID)));
+        assert_eq!(bus.next_local(), None);
+        let echo = sent.lock().unwrap()[0].clone();
+        assert!(bus.receive(&echo).unwrap().is_none());
+        assert_eq!(suppressed(&metrics, "echo"), 1);
+    }
+
+    #[test]
+    fn published_event_is_delivered_without_a_network() {
+        let (mut bus, _, _) = bus(true);
+        assert!(bus.publish(event(OWN_ID)).is_err());
+        assert_eq!(bus.next_local(), Some(event(OWN_ID)));
+    }
+
+    #[test]
+    fn copies_are_dropped() {
+        let (mut bus, _, metrics) = bus(false);
+        let envelope = Sealer::new(2, clock()).seal(&Message::Button(event(2)));
+        assert!(bus.receive(&envelope).unwrap().is_some());
+        assert!(bus.receive(&envelope).unwrap().is_none());
+        assert_eq!(suppressed(&metrics, "copy"), 1);
+        let next = Sealer::new(2, clock()).seal(&Message::Button(event(2)));
+        assert!(bus.receive(&next).unwrap().is_some());
+    }
+
+    #[test]
+    fn envelopes_of_another_elevator_with_the_same_id_are_dropped() {
+        let (mut bus, _, metrics) = bus(false);
+        bus.transmit(&Message::Button(event(OWN_ID))).unwrap();
+        let impostor = Sealer::new(OWN_ID, clock()).seal(&Message::Button(event(OWN_ID)));
+        assert!(bus.receive(&impostor).unwrap().is_none());
+        assert_eq!(suppressed(&metrics, "impostor"), 1);
+        assert_eq!(suppressed(&metrics, "echo"), 0);
+    }
+
+    #[test]
+    fn envelopes_are_checked_before_echoes_are_dropped() {
+        let (mut bus, sent, metrics) = bus(false);
+        bus.transmit(&Message::Button(event(OWN_ID))).unwrap();
+        let echo = sent.lock().unwrap()[0].clone();
+        let unsupported = Envelope{version: echo.version + 1, ..echo};
+        assert!(bus.receive(&unsupported).is_err());
+        assert_eq!(suppressed(&metrics, "echo"), 0);
+    }
+}
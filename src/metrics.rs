@@ -0,0 +1,201 @@
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::elev_controller::{ButtonType, RequestType};
+
+/// Upper bounds in seconds of the histogram buckets, an order normally takes seconds to tens of seconds.
+const BUCKETS: [f64; 11] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative, the last one counts everything above the largest bound.
+    buckets: [u64; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        let index = BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(BUCKETS.len());
+        self.buckets[index] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, self.sum, name, self.count);
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    orders: BTreeMap<String, u64>,
+    order_duration: Histogram,
+    delay_take: Histogram,
+    watchdog_takeovers: u64,
+    cab_rebroadcasts: u64,
+    door_cycles: u64,
+    packets_sent: BTreeMap<String, u64>,
+    packets_received: BTreeMap<String, u64>,
+    queue_length: usize,
+    ack_retransmissions: u64,
+    unacknowledged: usize,
+    digest_repairs: BTreeMap<String, u64>,
+    duplicates_suppressed: BTreeMap<String, u64>,
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+}
+
+fn render_labelled_counter(out: &mut String, name: &str, help: &str, label: &str, values: &BTreeMap<String, u64>) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+    for (value, count) in values {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, count);
+    }
+}
+
+/// Counters and histograms on how well orders are dispatched, served at `/metrics` in the
+/// Prometheus text format. Updated from the main loop, read from the HTTP thread.
+#[derive(Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+
+pub type SharedMetrics = Arc<Metrics>;
+
+impl Metrics {
+    pub fn shared() -> SharedMetrics {
+        Arc::new(Metrics::default())
+    }
+
+    pub fn order_requested(&self, order_type: &ButtonType) {
+        *self.registry.lock().unwrap().orders.entry(format!("{:?}", order_type)).or_insert(0) += 1;
+    }
+
+    /// An order reached `Complete`, `duration` after it was first seen.
+    pub fn order_completed(&self, duration: Duration) {
+        self.registry.lock().unwrap().order_duration.observe(duration);
+    }
+
+    pub fn delay_take_ended(&self, duration: Duration) {
+        self.registry.lock().unwrap().delay_take.observe(duration);
+    }
+
+    /// The complete watchdog of a hall call expired and this elevator took it over.
+    pub fn watchdog_takeover(&self) {
+        self.registry.lock().unwrap().watchdog_takeovers += 1;
+    }
+
+    pub fn cab_rebroadcast(&self) {
+        self.registry.lock().unwrap().cab_rebroadcasts += 1;
+    }
+
+    pub fn door_cycle(&self) {
+        self.registry.lock().unwrap().door_cycles += 1;
+    }
+
+    pub fn packet_sent(&self, request: &RequestType) {
+        *self.registry.lock().unwrap().packets_sent.entry(format!("{:?}", request)).or_insert(0) += 1;
+    }
+
+    pub fn packet_received(&self, request: &RequestType) {
+        *self.registry.lock().unwrap().packets_received.entry(format!("{:?}", request)).or_insert(0) += 1;
+    }
+
+    pub fn set_queue_length(&self, length: usize) {
+        self.registry.lock().unwrap().queue_length = length;
+    }
+
+    pub fn ack_retransmission(&self) {
+        self.registry.lock().unwrap().ack_retransmissions += 1;
+    }
+
+    pub fn set_unacknowledged(&self, count: usize) {
+        self.registry.lock().unwrap().unacknowledged = count;
+    }
+
+    /// The task list was corrected from the digest of a peer, `repair` is what was corrected.
+    pub fn digest_repair(&self, repair: &str) {
+        *self.registry.lock().unwrap().digest_repairs.entry(repair.to_string()).or_insert(0) += 1;
+    }
+
+    /// A received message was dropped as a duplicate, `reason` is `echo` for a broadcast of this elevator,
+    /// `copy` for a retransmission of a message already delivered and `impostor` for a message of another elevator with the same id.
+    pub fn duplicate_suppressed(&self, reason: &str) {
+        *self.registry.lock().unwrap().duplicates_suppressed.entry(reason.to_string()).or_insert(0) += 1;
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock().unwrap();
+        let mut out = String::new();
+        render_labelled_counter(&mut out, "elevator_orders_total", "Orders this elevator has learnt of, by button type.", "type", &registry.orders);
+        registry.order_duration.render(&mut out, "elevator_order_duration_seconds", "Time from an order being seen until it is complete.");
+        registry.delay_take.render(&mut out, "elevator_delay_take_seconds", "Time orders spent waiting for their cost function delay.");
+        render_counter(&mut out, "elevator_watchdog_takeovers_total", "Hall calls taken over after the elevator holding them did not serve them in time.", registry.watchdog_takeovers);
+        render_counter(&mut out, "elevator_cab_rebroadcasts_total", "Cab calls of other elevators broadcast again.", registry.cab_rebroadcasts);
+        render_counter(&mut out, "elevator_door_cycles_total", "Times the door was opened.", registry.door_cycles);
+        render_labelled_counter(&mut out, "elevator_packets_sent_total", "Button event packets sent, retransmissions included.", "request", &registry.packets_sent);
+        render_labelled_counter(&mut out, "elevator_packets_received_total", "Button event packets received from other elevators, retransmissions included.", "request", &registry.packets_received);
+        render_counter(&mut out, "elevator_ack_retransmissions_total", "Taken and Complete events sent again for lack of acknowledgement.", registry.ack_retransmissions);
+        render_labelled_counter(&mut out, "elevator_digest_repairs_total", "Orders added, taken or served after comparing the task list with the digest of a peer.", "repair", &registry.digest_repairs);
+        render_labelled_counter(&mut out, "elevator_duplicates_suppressed_total", "Received messages dropped as echoes of own broadcasts, copies of messages already delivered or sent by another elevator with the same id.", "reason", &registry.duplicates_suppressed);
+        let _ = writeln!(out, "# HELP elevator_unacknowledged_events Taken and Complete events not yet acknowledged by every live peer.\n# TYPE elevator_unacknowledged_events gauge\nelevator_unacknowledged_events {}", registry.unacknowledged);
+        let _ = writeln!(out, "# HELP elevator_queue_length Orders in the queue of the car.\n# TYPE elevator_queue_length gauge\nelevator_queue_length {}", registry.queue_length);
+        out
+    }
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "Only GET /metrics is served\n".to_string()),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)
+}
+
+/// Serves the metrics over HTTP on `address` from a thread of its own.
+/// Every connection is answered on a thread of its own, so a slow client does not hold up the others.
+pub fn serve(address: &str, metrics: SharedMetrics) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Metrics request failed: {}", e);
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            thread::spawn(move || {
+                if let Err(e) = respond(stream, &metrics) {
+                    warn!("Metrics request failed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use network_rust::bcast::BcastTransmitter;
+use log::warn;
+use std::io;
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::clock::SharedClock;
+use crate::config::NetworkConfig;
+use crate::delivery;
+use crate::metrics::SharedMetrics;
+use crate::protocol::{Message, Sealer};
+
+/// Sends messages to every elevator on the network. They reach this one too, the
+/// message bus drops them there.
+pub trait MessageTransmitter {
+    /// Sends the message, returns the id of the envelope it was sent in.
+    fn transmit(&self, message: &Message) -> io::Result<u64>;
+}
+
+/// Number of times a message is sent. Requests are repeated `retransmit_count` times
+/// to survive packet loss, heartbeats are periodic and a lost one is replaced by the next.
+/// `Taken` and `Complete` events are sent again by the task manager until they are acknowledged,
+/// and a lost acknowledgement is answered again on the next retransmission. Digests are periodic like heartbeats.
+pub fn copies_of(message: &Message, retransmit_count: u32) -> u32 {
+    match message {
+        Message::Button(event) if !delivery::needs_acknowledgement(event) => retransmit_count,
+        Message::Button(_) | Message::Heartbeat(_) | Message::Ack(_) | Message::Digest(_) => 1,
+    }
+}
+
+/// Transmits messages in protocol envelopes as UDP broadcasts.
+pub struct UdpTransmitter {
+    udp_broadcast_port: u16,
+    retransmit_count: u32,
+    retransmit_interval: Duration,
+    sealer: Sealer,
+    metrics: SharedMetrics,
+}
+
+impl UdpTransmitter {
+    pub fn new(network: &NetworkConfig, elevator_id: u32, clock: SharedClock, metrics: SharedMetrics) -> Self {
+        UdpTransmitter {
+            udp_broadcast_port: network.udp_broadcast_port,
+            retransmit_count: network.retransmit_count,
+            retransmit_interval: network.retransmit_interval(),
+            sealer: Sealer::new(elevator_id, clock),
+            metrics,
+        }
+    }
+}
+
+impl MessageTransmitter for UdpTransmitter {
+    fn transmit(&self, message: &Message) -> io::Result<u64> {
+        let broadcast = BcastTransmitter::new(self.udp_broadcast_port)?;
+        let data_block_network = self.sealer.seal(message);
+        // Send the first copy here so a dead network is reported to the caller
+        broadcast.transmit(&data_block_network)?;
+        let request = match message {
+            Message::Button(event) => Some(event.request.clone()),
+            Message::Heartbeat(_) | Message::Ack(_) | Message::Digest(_) => None,
+        };
+        if let Some(request) = &request {
+            self.metrics.packet_sent(request);
+        }
+        let message_id = data_block_network.message_id;
+        let copies = copies_of(message, self.retransmit_count);
+        let interval = self.retransmit_interval;
+        if copies == 1 {
+            return Ok(message_id);
+        }
+        let metrics = self.metrics.clone();
+        thread::spawn(move || {
+            for _ in 1..copies {
+                sleep(interval);
+                if let Err(e) = broadcast.transmit(&data_block_network) {
+                    warn!("Retransmit failed: {}", e);
+                    break;
+                }
+                if let Some(request) = &request {
+                    metrics.packet_sent(request);
+                }
+            }
+        });
+        Ok(message_id)
+    }
+}
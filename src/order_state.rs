@@ -0,0 +1,356 @@
+use serde::*;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use crate::elev_controller::{ButtonEvent, ButtonType, Order, OrderId, RequestType};
+
+/// How far an order has come, in the order orders go through the phases.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Unknown,
+    Requested,
+    Assigned,
+    Served,
+}
+
+/// Replicated state of one button. Every elevator keeps a copy, and copies are combined with
+/// `merge`, which takes the greatest of the two by version, then whether it is served, then
+/// releases, phase, assignee and id. States are totally ordered that way, so merging is commutative,
+/// associative and idempotent and the copies converge whatever order the states arrive in and however often they are repeated.
+///
+/// Changes only ever make the state greater: a new press of a served button starts a new version,
+/// releasing an assigned order counts up its releases, and the phase moves forward in between.
+/// A version served beats every release of it, so an order served while it was being released is not served again.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrderState {
+    /// Counts the presses that started the order anew after it was served.
+    pub version: u32,
+    /// Counts the times the order was given up by the elevator it was assigned to within this version.
+    pub releases: u32,
+    pub phase: Phase,
+    /// Elevator serving the order from `Assigned` on. Of two elevators taking an order at the
+    /// same time the one with the highest id is kept, the other serves it anyway.
+    pub assignee: Option<u32>,
+    /// The press the order was made by. Of two presses starting a version at the same time
+    /// the greatest id is kept, the other press has been served along with it.
+    pub id: Option<OrderId>,
+    /// When the button was pressed, in milliseconds since the unix epoch on the clock of the elevator it was pressed in.
+    pub created_ms: u64,
+}
+
+impl Default for OrderState {
+    fn default() -> Self {
+        OrderState{version: 0, releases: 0, phase: Phase::Unknown, assignee: None, id: None, created_ms: 0}
+    }
+}
+
+impl Ord for OrderState {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let key = |state: &OrderState| (state.version, state.is_served(), state.releases, state.phase, state.assignee, state.id, state.created_ms);
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for OrderState {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl OrderState {
+    /// The state a `Taken` or `Complete` event reports for the version and release it was made for.
+    /// Merged into a state released since, a `Taken` changes nothing and a `Complete` still serves the order.
+    pub fn reported_by(event: &ButtonEvent) -> OrderState {
+        let (phase, assignee) = match event.request {
+            RequestType::Request => (Phase::Requested, None),
+            RequestType::Taken => (Phase::Assigned, Some(event.origin)),
+            RequestType::Complete => (Phase::Served, Some(event.origin)),
+        };
+        OrderState{version: event.version, releases: event.releases, phase, assignee, id: Some(event.id), created_ms: event.created_ms}
+    }
+
+    /// Combines another copy of the state into this one, returns whether this one changed.
+    pub fn merge(&mut self, other: &OrderState) -> bool {
+        if *other > *self {
+            *self = *other;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The button was pressed. A served order starts over in a new version with the id of the press,
+    /// an order still being served is left as it is.
+    pub fn requested(&self, id: OrderId, created_ms: u64) -> OrderState {
+        match self.phase {
+            Phase::Unknown => OrderState{version: self.version, releases: 0, phase: Phase::Requested, assignee: None, id: Some(id), created_ms},
+            Phase::Served => OrderState{version: self.version + 1, releases: 0, phase: Phase::Requested, assignee: None, id: Some(id), created_ms},
+            Phase::Requested | Phase::Assigned => *self,
+        }
+    }
+
+    pub fn assigned(&self, elevator_id: u32) -> OrderState {
+        OrderState{phase: Phase::Assigned, assignee: Some(elevator_id), ..*self}
+    }
+
+    pub fn served(&self) -> OrderState {
+        OrderState{phase: Phase::Served, ..*self}
+    }
+
+    /// The elevator the order was assigned to can not serve it, anybody may take it again.
+    /// It is still the same press and version, so the id is kept.
+    pub fn released(&self) -> OrderState {
+        OrderState{releases: self.releases + 1, phase: Phase::Requested, assignee: None, ..*self}
+    }
+
+    pub fn is_taken(&self) -> bool {
+        self.phase >= Phase::Assigned
+    }
+
+    pub fn is_served(&self) -> bool {
+        self.phase == Phase::Served
+    }
+
+    /// Whether the order waits to be served.
+    pub fn is_active(&self) -> bool {
+        self.phase == Phase::Requested || self.phase == Phase::Assigned
+    }
+}
+
+/// Whether two orders are the same button. Cab calls are told apart by the elevator they were
+/// made in, hall calls are shared by every elevator.
+pub fn same_order(order: &Order, origin: u32, other: &Order, other_origin: u32) -> bool {
+    order == other && (order.order_type != ButtonType::CabCall || origin == other_origin)
+}
+
+/// The replicated state of one button, as sent in digests.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplicatedOrder {
+    pub order: Order,
+    /// Elevator the order was made in, which tells cab calls apart.
+    pub origin: u32,
+    pub state: OrderState,
+}
+
+/// How a button event relates to the order of its button, see `OrderBook::classify`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Press {
+    /// A press not heard of before. It starts a new version of a served order, or joins the order still waiting.
+    New,
+    /// A press of the current version of the order.
+    Current,
+    /// A retransmission or a late copy of an event about an earlier version, which is ignored.
+    Stale,
+}
+
+struct Entry {
+    replicated: ReplicatedOrder,
+    /// Newest press heard of per elevator. Sequences only increase, so an older one is not new.
+    newest: BTreeMap<u32, u64>,
+    /// Oldest press per elevator heard of since the current version of the order was pressed.
+    current: BTreeMap<u32, u64>,
+}
+
+impl Entry {
+    fn note(&mut self, id: OrderId) {
+        let newest = self.newest.entry(id.origin).or_insert(id.sequence);
+        *newest = (*newest).max(id.sequence);
+        let current = self.current.entry(id.origin).or_insert(id.sequence);
+        *current = (*current).min(id.sequence);
+    }
+
+    /// Replaces the state, forgetting the presses of an earlier version if the button has been pressed again since.
+    fn set_state(&mut self, origin: u32, state: OrderState) {
+        if state.version > self.replicated.state.version && state.id != self.replicated.state.id {
+            self.current.clear();
+        }
+        self.replicated.origin = origin;
+        self.replicated.state = state;
+        if let Some(id) = state.id {
+            self.note(id);
+        }
+    }
+}
+
+/// The state of every button this elevator has heard of. Buttons are never forgotten, there
+/// are only a few per floor and elevator, so a served order is still known when a stale copy of it turns up.
+#[derive(Default)]
+pub struct OrderBook {
+    entries: Vec<Entry>,
+}
+
+impl OrderBook {
+    pub fn new() -> Self {
+        OrderBook::default()
+    }
+
+    fn entry(&self, order: &Order, origin: u32) -> Option<&Entry> {
+        self.entries.iter().find(|entry| same_order(&entry.replicated.order, entry.replicated.origin, order, origin))
+    }
+
+    fn entry_mut(&mut self, order: &Order, origin: u32) -> &mut Entry {
+        let index = match self.entries.iter().position(|entry| same_order(&entry.replicated.order, entry.replicated.origin, order, origin)) {
+            Some(index) => index,
+            None => {
+                let replicated = ReplicatedOrder{order: order.clone(), origin, state: OrderState::default()};
+                self.entries.push(Entry{replicated, newest: BTreeMap::new(), current: BTreeMap::new()});
+                self.entries.len() - 1
+            }
+        };
+        &mut self.entries[index]
+    }
+
+    pub fn state(&self, order: &Order, origin: u32) -> OrderState {
+        self.entry(order, origin).map(|entry| entry.replicated.state).unwrap_or_default()
+    }
+
+    /// The duplicate policy: a press newer than every press heard of from its elevator is new,
+    /// one heard of since the current version of the order was pressed belongs to it,
+    /// and anything else is about an order served before.
+    pub fn classify(&self, order: &Order, origin: u32, id: &OrderId) -> Press {
+        let entry = match self.entry(order, origin) {
+            Some(entry) => entry,
+            None => return Press::New,
+        };
+        match (entry.newest.get(&id.origin), entry.current.get(&id.origin)) {
+            (Some(newest), _) if id.sequence > *newest => Press::New,
+            (None, _) => Press::New,
+            (_, Some(current)) if id.sequence >= *current => Press::Current,
+            _ => Press::Stale,
+        }
+    }
+
+    /// Records a new press of the button, see `OrderState::requested`.
+    pub fn request(&mut self, order: &Order, origin: u32, id: OrderId, created_ms: u64) {
+        let entry = self.entry_mut(order, origin);
+        let state = entry.replicated.state.requested(id, created_ms);
+        entry.set_state(origin, state);
+        entry.note(id);
+    }
+
+    /// Merges a state of the order into the book, returns whether the book changed.
+    pub fn merge(&mut self, order: &Order, origin: u32, state: &OrderState) -> bool {
+        if *state == OrderState::default() {
+            return false;
+        }
+        let entry = self.entry_mut(order, origin);
+        let mut merged = entry.replicated.state;
+        if !merged.merge(state) {
+            return false;
+        }
+        entry.set_state(origin, merged);
+        true
+    }
+
+    /// Applies a change to the state of the order, returns whether the book changed.
+    pub fn update<F: FnOnce(&OrderState) -> OrderState>(&mut self, order: &Order, origin: u32, change: F) -> bool {
+        let state = change(&self.state(order, origin));
+        self.merge(order, origin, &state)
+    }
+
+    pub fn orders(&self) -> Vec<ReplicatedOrder> {
+        self.entries.iter().filter(|entry| entry.replicated.state != OrderState::default()).map(|entry| entry.replicated.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(sequence: u64) -> OrderId {
+        OrderId{origin: 1, sequence}
+    }
+
+    fn event(request: RequestType, origin: u32, state: &OrderState) -> ButtonEvent {
+        let order = Order{floor: 2, order_type: ButtonType::HallUpCall};
+        ButtonEvent{request, order, origin, id: state.id.unwrap(), version: state.version, releases: state.releases, created_ms: state.created_ms}
+    }
+
+    fn merged(states: &[OrderState]) -> OrderState {
+        let mut merged = OrderState::default();
+        for state in states {
+            merged.merge(state);
+        }
+        merged
+    }
+
+    fn hall_call() -> Order {
+        Order{floor: 2, order_type: ButtonType::HallUpCall}
+    }
+
+    #[test]
+    fn classifies_presses_of_the_current_version() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.classify(&hall_call(), 1, &press(1)), Press::New);
+        book.request(&hall_call(), 1, press(1), 0);
+        assert_eq!(book.classify(&hall_call(), 1, &press(1)), Press::Current);
+        // Pressed again while waiting, the press joins the order
+        assert_eq!(book.classify(&hall_call(), 1, &press(2)), Press::New);
+        book.request(&hall_call(), 1, press(2), 10);
+        assert_eq!(book.state(&hall_call(), 1).id, Some(press(1)));
+        assert_eq!(book.classify(&hall_call(), 1, &press(1)), Press::Current);
+        assert_eq!(book.classify(&hall_call(), 1, &press(2)), Press::Current);
+    }
+
+    #[test]
+    fn classifies_presses_of_a_served_version_as_stale() {
+        let mut book = OrderBook::new();
+        book.request(&hall_call(), 1, press(1), 0);
+        book.update(&hall_call(), 1, |state| state.assigned(2).served());
+        assert_eq!(book.classify(&hall_call(), 1, &press(2)), Press::New);
+        book.request(&hall_call(), 1, press(2), 10);
+        assert_eq!(book.state(&hall_call(), 1).version, 1);
+        assert_eq!(book.classify(&hall_call(), 1, &press(2)), Press::Current);
+        assert_eq!(book.classify(&hall_call(), 1, &press(1)), Press::Stale);
+    }
+
+    #[test]
+    fn late_copy_of_an_earlier_press_after_a_new_press_is_stale() {
+        let mut book = OrderBook::new();
+        book.request(&hall_call(), 1, press(2), 10);
+        // A copy of press 1 delayed in the network, press 2 was made after it
+        assert_eq!(book.classify(&hall_call(), 1, &press(1)), Press::Stale);
+        assert_eq!(book.state(&hall_call(), 1).id, Some(press(2)));
+    }
+
+    #[test]
+    fn late_taken_does_not_assign_a_released_order_again() {
+        let taken = OrderState::default().requested(press(1), 0).assigned(2);
+        let mut state = taken.released();
+        assert!(!state.merge(&OrderState::reported_by(&event(RequestType::Taken, 2, &taken))));
+        assert_eq!(state.phase, Phase::Requested);
+        assert_eq!(state.assignee, None);
+    }
+
+    #[test]
+    fn served_beats_a_concurrent_release() {
+        let taken = OrderState::default().requested(press(1), 0).assigned(2);
+        let released = taken.released().assigned(3);
+        let served = OrderState::reported_by(&event(RequestType::Complete, 2, &taken));
+        assert!(merged(&[released, served]).is_served());
+        assert!(merged(&[served, released]).is_served());
+    }
+
+    #[test]
+    fn merge_converges_in_any_order() {
+        let requested = OrderState::default().requested(press(1), 0);
+        let taken = requested.assigned(2);
+        let released = taken.released();
+        let retaken = released.assigned(3);
+        let served = taken.served();
+        let pressed_again = served.requested(press(2), 10);
+        let states = [requested, taken, released, retaken, served, pressed_again];
+        let expected = merged(&states);
+        assert_eq!(expected, pressed_again);
+        for first in 0..states.len() {
+            for second in 0..states.len() {
+                let mut reordered = states.to_vec();
+                reordered.swap(0, first);
+                reordered.swap(1, second);
+                reordered.reverse();
+                assert_eq!(merged(&reordered), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use serde::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use std::time::SystemTime;
+
+use crate::building::Building;
+use crate::elev_controller::TravelDirection;
+
+/// How often every elevator announces itself.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+/// A peer not heard from for this long might be gone.
+const SUSPECT_AFTER: Duration = Duration::from_secs(1);
+/// A peer not heard from for this long is considered gone and its orders are reassigned.
+const DEAD_AFTER: Duration = Duration::from_secs(3);
+
+/// State of an elevator, sent periodically to every other elevator.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Heartbeat {
+    pub elevator_id: u32,
+    /// Last floor the car passed, -1 if it has not been at a floor yet.
+    pub floor: isize,
+    pub direction: TravelDirection,
+    pub door_open: bool,
+    pub queue_length: usize,
+    /// The car can not serve orders, its hall calls should be served by others.
+    pub out_of_service: bool,
+    /// Building the elevator was started with. Required, a heartbeat without it is rejected as malformed.
+    pub building: Building,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub heartbeat: Heartbeat,
+    pub last_seen: SystemTime,
+    pub state: PeerState,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerEvent {
+    Joined(u32),
+    Left(u32),
+    OutOfService(u32),
+    /// The peer describes another building and is ignored.
+    Refused(u32),
+}
+
+/// Every other elevator this one has heard from, ordered by id.
+pub struct PeerTable {
+    own_id: u32,
+    peers: BTreeMap<u32, Peer>,
+    building: Building,
+    /// Peers whose building does not match, kept out of `peers` until it does.
+    refused: BTreeSet<u32>,
+}
+
+impl PeerTable {
+    pub fn new(own_id: u32) -> Self {
+        PeerTable{own_id, peers: BTreeMap::new(), building: Building::default(), refused: BTreeSet::new()}
+    }
+
+    pub fn set_building(&mut self, building: Building) {
+        self.building = building;
+    }
+
+    /// Records a heartbeat, returns `Joined` if the peer is new or comes back from the dead,
+    /// and `OutOfService` if a known peer has just gone out of service.
+    /// A peer with another building is dropped and `Refused` is returned the first time.
+    pub fn heartbeat_received(&mut self, heartbeat: Heartbeat, now: SystemTime) -> Option<PeerEvent> {
+        if heartbeat.elevator_id == self.own_id {
+            return None;
+        }
+        let id = heartbeat.elevator_id;
+        if heartbeat.building != self.building {
+            self.peers.remove(&id);
+            return match self.refused.insert(id) {
+                true => Some(PeerEvent::Refused(id)),
+                false => None,
+            };
+        }
+        self.refused.remove(&id);
+        let out_of_service = heartbeat.out_of_service;
+        let previous = self.peers.insert(id, Peer{heartbeat, last_seen: now, state: PeerState::Alive});
+        match previous {
+            Some(ref peer) if peer.state != PeerState::Dead => {
+                if out_of_service && !peer.heartbeat.out_of_service {
+                    Some(PeerEvent::OutOfService(id))
+                } else {
+                    None
+                }
+            }
+            _ => Some(PeerEvent::Joined(id)),
+        }
+    }
+
+    /// Moves peers which have been silent too long to suspect or dead, returns the peers that died.
+    pub fn update(&mut self, now: SystemTime) -> Vec<PeerEvent> {
+        let mut events = vec![];
+        for (id, peer) in self.peers.iter_mut() {
+            let silent = now.duration_since(peer.last_seen).unwrap_or(Duration::from_secs(0));
+            let state = if silent > DEAD_AFTER {
+                PeerState::Dead
+            } else if silent > SUSPECT_AFTER {
+                PeerState::Suspect
+            } else {
+                PeerState::Alive
+            };
+            if state == PeerState::Dead && peer.state != PeerState::Dead {
+                events.push(PeerEvent::Left(*id));
+            }
+            peer.state = state;
+        }
+        events
+    }
+
+    pub fn is_refused(&self, id: u32) -> bool {
+        self.refused.contains(&id)
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Peer> {
+        self.peers.get(&id)
+    }
+
+    /// Peers which are not dead, suspects included since they are most likely just slow.
+    pub fn live_peers(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.values().filter(|peer| peer.state != PeerState::Dead)
+    }
+
+    /// Live peers which are able to serve orders.
+    pub fn serving_peers(&self) -> impl Iterator<Item = &Peer> {
+        self.live_peers().filter(|peer| !peer.heartbeat.out_of_service)
+    }
+}
@@ -0,0 +1,172 @@
+use serde::*;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use crate::clock::SharedClock;
+use crate::digest::Digest;
+use crate::elev_controller::ButtonEvent;
+use crate::peers::Heartbeat;
+
+/// Identifies packets belonging to this protocol, "ELEV" in ASCII.
+pub const MAGIC: u32 = 0x454c_4556;
+/// Version written in every envelope. Bump it in the change that adds a message or changes the
+/// fields or the meaning of one, and raise `MIN_PROTOCOL_VERSION` along with it unless older nodes still read the new messages right.
+/// The encoding of every message is pinned to the version by a test, which fails until both are updated.
+///
+/// 1. Button events.
+/// 2. Heartbeats.
+/// 3. Heartbeats tell whether the car is out of service.
+/// 4. Heartbeats carry the building.
+/// 5. Acknowledgements of `Taken` and `Complete`, and heartbeats tell whether the elevator sends them.
+/// 6. Digests of the task list.
+/// 7. Digests carry the replicated state of every order instead.
+/// 8. Button events carry the id and time of the press.
+/// 9. `Taken` and `Complete` carry the version and releases of the order they were made for,
+///    and a served order beats a release of it when order states are merged.
+/// 10. Heartbeats no longer tell whether the elevator acknowledges events, every supported version does.
+///
+/// Builds from before this list numbered their versions otherwise, all of them are older than `MIN_PROTOCOL_VERSION`.
+pub const PROTOCOL_VERSION: u16 = 10;
+/// Oldest version this node still understands.
+pub const MIN_PROTOCOL_VERSION: u16 = 10;
+
+/// Header wrapped around every message sent between elevators.
+/// The payload is kept encoded until the header has been checked, so a packet from a node
+/// running an incompatible version is rejected instead of being misinterpreted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    pub magic: u32,
+    pub version: u16,
+    /// Random id of the message, the same in every retransmission of it.
+    pub message_id: u64,
+    pub sender_id: u32,
+    /// Increases by one for every message the sender sends.
+    pub sequence: u64,
+    /// Sender wall clock time in milliseconds since the unix epoch.
+    pub sent_at_ms: u64,
+    pub payload: String,
+}
+
+/// Everything that can be carried in an envelope.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    Button(ButtonEvent),
+    Heartbeat(Heartbeat),
+    Ack(Ack),
+    Digest(Digest),
+}
+
+/// Tells the origin of a `Taken` or `Complete` event that it has arrived.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ack {
+    pub event: ButtonEvent,
+    pub acked_by: u32,
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    BadMagic(u32),
+    UnsupportedVersion(u16),
+    BadPayload(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::BadMagic(magic) => write!(f, "unknown magic number {:#x}", magic),
+            ProtocolError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {}, supported {}..={}", version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            ProtocolError::BadPayload(e) => write!(f, "malformed payload: {}", e),
+        }
+    }
+}
+
+impl Envelope {
+    /// Checks the header and decodes the payload.
+    pub fn open(&self) -> Result<Message, ProtocolError> {
+        if self.magic != MAGIC {
+            return Err(ProtocolError::BadMagic(self.magic));
+        }
+        if self.version < MIN_PROTOCOL_VERSION || self.version > PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(self.version));
+        }
+        serde_json::from_str(&self.payload).map_err(|e| ProtocolError::BadPayload(e.to_string()))
+    }
+}
+
+/// Wraps messages from one elevator in envelopes.
+pub struct Sealer {
+    sender_id: u32,
+    sequence: AtomicU64,
+    clock: SharedClock,
+}
+
+impl Sealer {
+    pub fn new(sender_id: u32, clock: SharedClock) -> Self {
+        Sealer{sender_id, sequence: AtomicU64::new(0), clock}
+    }
+
+    pub fn seal(&self, message: &Message) -> Envelope {
+        let sent_at_ms = match self.clock.now().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(time) => time.as_millis() as u64,
+            Err(_) => 0,
+        };
+        Envelope {
+            magic: MAGIC,
+            version: PROTOCOL_VERSION,
+            message_id: rand::random::<u64>(),
+            sender_id: self.sender_id,
+            sequence: self.sequence.fetch_add(1, Ordering::SeqCst),
+            sent_at_ms,
+            // Encoding plain data structures to JSON does not fail
+            payload: serde_json::to_string(message).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::building::Building;
+    use crate::elev_controller::{ButtonType, Order, OrderId, RequestType, TravelDirection};
+    use crate::order_state::{OrderState, ReplicatedOrder};
+
+    fn samples() -> Vec<Message> {
+        let event = ButtonEvent{request: RequestType::Taken, order: Order{floor: 2, order_type: ButtonType::HallUpCall}, origin: 1,
+                                id: OrderId{origin: 1, sequence: 5}, version: 1, releases: 2, created_ms: 1000};
+        let heartbeat = Heartbeat{elevator_id: 1, floor: 2, direction: TravelDirection::Up, door_open: false, queue_length: 1,
+                                  out_of_service: false, building: Building{floors: 4, ..Building::default()}};
+        let state = OrderState::reported_by(&event);
+        let digest = Digest{elevator_id: 1, orders: vec![ReplicatedOrder{order: event.order.clone(), origin: 1, state}]};
+        vec![Message::Button(event.clone()), Message::Heartbeat(heartbeat), Message::Ack(Ack{event, acked_by: 2}), Message::Digest(digest)]
+    }
+
+    /// Update both the version and the encodings here when a message changes, see `PROTOCOL_VERSION`.
+    #[test]
+    fn encoding_is_pinned_to_the_version() {
+        let encoded: Vec<String> = samples().iter().map(|message| serde_json::to_string(message).unwrap()).collect();
+        let event = r#"{"request":"Taken","order":{"floor":2,"order_type":"HallUpCall"},"origin":1,"id":{"origin":1,"sequence":5},"version":1,"releases":2,"created_ms":1000}"#;
+        let expected = vec![
+            format!(r#"{{"Button":{}}}"#, event),
+            r#"{"Heartbeat":{"elevator_id":1,"floor":2,"direction":"Up","door_open":false,"queue_length":1,"out_of_service":false,"building":{"floors":4,"no_up_button":[],"no_down_button":[]}}}"#.to_string(),
+            format!(r#"{{"Ack":{{"event":{},"acked_by":2}}}}"#, event),
+            r#"{"Digest":{"elevator_id":1,"orders":[{"order":{"floor":2,"order_type":"HallUpCall"},"origin":1,"state":{"version":1,"releases":2,"phase":"assigned","assignee":1,"id":{"origin":1,"sequence":5},"created_ms":1000}}]}}"#.to_string(),
+        ];
+        assert_eq!((PROTOCOL_VERSION, encoded), (10, expected));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let clock: SharedClock = std::sync::Arc::new(crate::clock::SystemClock);
+        for message in samples() {
+            let envelope = Sealer::new(1, clock.clone()).seal(&message);
+            assert!(envelope.open().is_ok());
+            for version in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1].iter() {
+                match (Envelope{version: *version, ..envelope.clone()}).open() {
+                    Err(ProtocolError::UnsupportedVersion(rejected)) => assert_eq!(rejected, *version),
+                    other => panic!("version {} was not rejected: {:?}", version, other),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use elevator_driver::elev_driver::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::time::SystemTime;
+
+use crate::assignment::AssignmentMode;
+use crate::clock::{Clock, SharedClock, VirtualClock};
+use crate::config::{Config, NetworkConfig};
+use crate::elev_controller::{ButtonEvent, ButtonType, Order, RequestType, ServiceMode};
+use crate::elev_simulator::{SimElevator, DEFAULT_TRAVEL_TIME};
+use crate::network::{copies_of, MessageTransmitter};
+use crate::protocol::{Envelope, Message, Sealer};
+use crate::task_manager::TaskManager;
+
+/// Virtual time between two runs of every state machine.
+const TICK: Duration = Duration::from_millis(10);
+const NETWORK_LATENCY: Duration = Duration::from_millis(1);
+
+/// A button pressed on one of the simulated elevators.
+#[derive(Debug, Clone)]
+pub struct ScriptedPress {
+    pub at: Duration,
+    pub elevator: usize,
+    pub order: Order,
+}
+
+/// Drops every packet received by one elevator while active, like `net_block.sh`.
+#[derive(Debug, Clone)]
+pub struct NetworkBlock {
+    pub elevator: usize,
+    pub from: Duration,
+    pub until: Duration,
+}
+
+/// Everything needed to reproduce a simulation run.
+/// Elevator `n` in the scenario runs with elevator id `n + 1`.
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    pub elevators: usize,
+    pub seed: u64,
+    /// Probability of each received packet being dropped, like `net_prob_drop.sh`.
+    pub packet_loss: f64,
+    pub blocks: Vec<NetworkBlock>,
+    pub presses: Vec<ScriptedPress>,
+    pub duration: Duration,
+    pub assignment_mode: AssignmentMode,
+    pub service_mode: ServiceMode,
+    /// Applied to every elevator like a configuration file, the building, timing, retransmissions and cost function included.
+    pub config: Config,
+}
+
+#[derive(Debug, Clone)]
+pub struct OrderLatency {
+    pub press: ScriptedPress,
+    /// Time from the press until the order was completed, `None` if it never was.
+    pub latency: Option<Duration>,
+}
+
+struct SimBus {
+    rng: StdRng,
+    clock: VirtualClock,
+    start: SystemTime,
+    packet_loss: f64,
+    blocks: Vec<NetworkBlock>,
+    in_flight: Vec<(SystemTime, usize, Envelope)>,
+    completions: Vec<(SystemTime, ButtonEvent)>,
+    elevators: usize,
+    /// Retransmissions are done like `UdpTransmitter` does with the configuration of the scenario.
+    network: NetworkConfig,
+}
+
+impl SimBus {
+    /// Delivers `envelope` to every elevator, the sender included like a UDP broadcast.
+    fn send(&mut self, message: &Message, envelope: Envelope) {
+        let now = self.clock.now();
+        if let Message::Button(event @ ButtonEvent{request: RequestType::Complete, ..}) = message {
+            self.completions.push((now, event.clone()));
+        }
+        for copy in 0..copies_of(message, self.network.retransmit_count) {
+            let arrival = now + self.network.retransmit_interval() * copy + NETWORK_LATENCY;
+            for receiver in 0..self.elevators {
+                if self.rng.gen::<f64>() >= self.packet_loss {
+                    self.in_flight.push((arrival, receiver, envelope.clone()));
+                }
+            }
+        }
+    }
+
+    fn is_blocked(&self, elevator: usize, at: SystemTime) -> bool {
+        let since_start = at.duration_since(self.start).unwrap_or(Duration::from_secs(0));
+        self.blocks.iter().any(|block| block.elevator == elevator && block.from <= since_start && since_start < block.until)
+    }
+
+    fn take_due(&mut self) -> Vec<(usize, Envelope)> {
+        let now = self.clock.now();
+        let (due, pending): (Vec<_>, Vec<_>) = self.in_flight.drain(..).partition(|(arrival, _, _)| *arrival <= now);
+        self.in_flight = pending;
+        due.into_iter()
+            .filter(|(arrival, receiver, _)| !self.is_blocked(*receiver, *arrival))
+            .map(|(_, receiver, envelope)| (receiver, envelope))
+            .collect()
+    }
+}
+
+struct SimBusTransmitter {
+    bus: Arc<Mutex<SimBus>>,
+    sealer: Sealer,
+}
+
+impl MessageTransmitter for SimBusTransmitter {
+    fn transmit(&self, message: &Message) -> io::Result<u64> {
+        let mut bus = self.bus.lock().unwrap();
+        // Message ids come from the scenario seed, the bus drops copies by id
+        let envelope = Envelope{message_id: bus.rng.gen(), ..self.sealer.seal(message)};
+        let message_id = envelope.message_id;
+        bus.send(message, envelope);
+        Ok(message_id)
+    }
+}
+
+struct SimNode {
+    manager: TaskManager<SimElevator>,
+    elevator: SimElevator,
+}
+
+impl Scenario {
+    /// Three elevators with 20% of all received packets dropped.
+    pub fn packet_loss(seed: u64) -> Self {
+        Scenario {
+            elevators: 3,
+            seed,
+            packet_loss: 0.2,
+            blocks: vec![],
+            presses: default_presses(),
+            duration: Duration::from_secs(120),
+            assignment_mode: AssignmentMode::TimerRace,
+            service_mode: ServiceMode::Collective,
+            config: Config::default(),
+        }
+    }
+
+    /// Three elevators where the first one loses all network traffic for 40 seconds.
+    pub fn network_block(seed: u64) -> Self {
+        Scenario {
+            elevators: 3,
+            seed,
+            packet_loss: 0.0,
+            blocks: vec![NetworkBlock{elevator: 0, from: Duration::from_secs(5), until: Duration::from_secs(45)}],
+            presses: default_presses(),
+            duration: Duration::from_secs(120),
+            assignment_mode: AssignmentMode::TimerRace,
+            service_mode: ServiceMode::Collective,
+            config: Config::default(),
+        }
+    }
+}
+
+fn default_presses() -> Vec<ScriptedPress> {
+    let press = |at_ms: u64, elevator: usize, floor: u8, order_type: ButtonType| {
+        ScriptedPress{at: Duration::from_millis(at_ms), elevator, order: Order{floor, order_type}}
+    };
+    vec![
+        press(1000, 0, 3, ButtonType::HallDownCall),
+        press(2000, 1, 2, ButtonType::CabCall),
+        press(6000, 2, 1, ButtonType::HallUpCall),
+        press(8000, 0, 1, ButtonType::CabCall),
+        press(12000, 1, 0, ButtonType::HallUpCall),
+        press(20000, 2, 3, ButtonType::CabCall),
+        press(25000, 0, 2, ButtonType::HallDownCall),
+        press(30000, 1, 2, ButtonType::HallUpCall),
+    ]
+}
+
+fn to_button(order: &Order) -> Button {
+    match order.order_type {
+        ButtonType::CabCall => Button::Internal(Floor::At(order.floor)),
+        ButtonType::HallUpCall => Button::CallUp(Floor::At(order.floor)),
+        ButtonType::HallDownCall => Button::CallDown(Floor::At(order.floor)),
+    }
+}
+
+/// Runs every elevator in the scenario in this process on a virtual clock.
+/// The same scenario always gives the same result.
+pub fn run(scenario: &Scenario) -> Vec<OrderLatency> {
+    let clock = VirtualClock::new(SystemTime::UNIX_EPOCH);
+    let shared_clock: SharedClock = Arc::new(clock.clone());
+    let start = clock.now();
+    let bus = Arc::new(Mutex::new(SimBus {
+        rng: StdRng::seed_from_u64(scenario.seed),
+        clock: clock.clone(),
+        start,
+        packet_loss: scenario.packet_loss,
+        blocks: scenario.blocks.clone(),
+        in_flight: vec![],
+        completions: vec![],
+        elevators: scenario.elevators,
+        network: scenario.config.network.clone(),
+    }));
+
+    let mut nodes: Vec<SimNode> = vec![];
+    for index in 0..scenario.elevators {
+        let elevator_id = index as u32 + 1;
+        let elevator = SimElevator::new(scenario.config.building.floors, DEFAULT_TRAVEL_TIME, shared_clock.clone());
+        let transmitter = Box::new(SimBusTransmitter{bus: bus.clone(), sealer: Sealer::new(elevator_id, shared_clock.clone())});
+        let mut manager = TaskManager::new(elevator_id, transmitter, elevator.clone(), shared_clock.clone(), None).unwrap();
+        manager.set_assignment_mode(scenario.assignment_mode);
+        manager.set_service_mode(scenario.service_mode);
+        manager.set_config(&scenario.config);
+        nodes.push(SimNode{manager, elevator});
+    }
+
+    // Presses of buttons the building does not have are left out of the run and the results
+    let mut presses: Vec<ScriptedPress> = scenario.presses.iter().filter(|press| scenario.config.building.has_button_for(&press.order)).cloned().collect();
+    presses.sort_by_key(|press| press.at);
+    let mut next_press = 0;
+    let mut elapsed = Duration::from_secs(0);
+    while elapsed < scenario.duration {
+        while next_press < presses.len() && presses[next_press].at <= elapsed {
+            let press = &presses[next_press];
+            nodes[press.elevator].elevator.press_button(to_button(&press.order)).unwrap();
+            next_press += 1;
+        }
+        let delivered = bus.lock().unwrap().take_due();
+        for (receiver, envelope) in delivered {
+            nodes[receiver].manager.handle_envelope(envelope);
+        }
+        for node in nodes.iter_mut() {
+            node.manager.deliver_local_events();
+            node.manager.run_state_machine().unwrap();
+        }
+        clock.advance(TICK);
+        elapsed += TICK;
+    }
+
+    let completions = &bus.lock().unwrap().completions;
+    presses.into_iter().map(|press| {
+        let pressed_at = start + press.at;
+        let completed_at = completions.iter().find(|(at, event)| {
+            *at >= pressed_at && event.order == press.order &&
+                (press.order.order_type != ButtonType::CabCall || event.origin == press.elevator as u32 + 1)
+        }).map(|(at, _)| *at);
+        let latency = completed_at.map(|at| at.duration_since(pressed_at).unwrap());
+        OrderLatency{press, latency}
+    }).collect()
+}
+
+pub fn print_report(results: &[OrderLatency]) {
+    for result in results {
+        match result.latency {
+            Some(latency) => println!("[sim_harness] {:?} at elevator {} pressed at {:?} served after {:?}", result.press.order, result.press.elevator, result.press.at, latency),
+            None => println!("[sim_harness] {:?} at elevator {} pressed at {:?} was never served", result.press.order, result.press.elevator, result.press.at),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latencies(results: &[OrderLatency]) -> Vec<Option<Duration>> {
+        results.iter().map(|result| result.latency).collect()
+    }
+
+    fn assert_every_press_served(results: &[OrderLatency]) {
+        for result in results {
+            assert!(result.latency.is_some(), "{:?} at elevator {} pressed at {:?} was never served", result.press.order, result.press.elevator, result.press.at);
+        }
+    }
+
+    #[test]
+    fn packet_loss_serves_every_press() {
+        let results = run(&Scenario::packet_loss(1));
+        assert_eq!(results.len(), default_presses().len());
+        assert_every_press_served(&results);
+    }
+
+    #[test]
+    fn network_block_serves_every_press() {
+        let results = run(&Scenario::network_block(1));
+        assert_eq!(results.len(), default_presses().len());
+        assert_every_press_served(&results);
+    }
+
+    #[test]
+    fn runs_in_the_building_of_the_configuration() {
+        let mut scenario = Scenario::packet_loss(1);
+        scenario.config.building.floors = 3;
+        let results = run(&scenario);
+        let in_building = default_presses().iter().filter(|press| scenario.config.building.has_button_for(&press.order)).count();
+        assert!(in_building < default_presses().len());
+        assert_eq!(results.len(), in_building);
+        assert_every_press_served(&results);
+    }
+
+    #[test]
+    fn same_seed_gives_same_latencies() {
+        for scenario in [Scenario::packet_loss(7), Scenario::network_block(7)].iter() {
+            assert_eq!(latencies(&run(scenario)), latencies(&run(scenario)));
+        }
+    }
+}
@@ -1,26 +1,48 @@
-use std::io;
 use elevator_driver::*;
-use std::sync::mpsc::*;
+use log::{debug, error, info, warn};
+use serde::*;
+use std::collections::BTreeSet;
 use std::vec::Vec;
 use std::time::Duration;
 use std::time::SystemTime;
-use std::collections::VecDeque;
 
+use crate::assignment::{self, AssignmentMode};
+use crate::building::Building;
+use crate::clock::SharedClock;
+use crate::config::{Config, CostConfig, NetworkConfig, OrderConfig};
+use crate::cost_function::{CarState, CostFunction};
+use crate::delivery::{self, PendingAcks};
+use crate::digest::Digest;
 use crate::elev_controller;
+use crate::elev_hardware::ElevHardware;
+use crate::error::ElevResult;
+use crate::event_log::{EventKind, EventRecorder, EventSink};
+use crate::journal::{Journal, JournalEntry};
+use crate::metrics::{Metrics, SharedMetrics};
+use crate::network::MessageTransmitter;
+use crate::order_state::{self, OrderBook, OrderState, Press};
+use crate::peers::{Heartbeat, PeerEvent, PeerTable, HEARTBEAT_INTERVAL};
+use crate::protocol::{Ack, Envelope, Message};
 
 #[derive(PartialEq, Clone, Debug)]
 struct Task {
     order: elev_controller::Order,
     state: States,
-    taken: bool,
-    complete: bool,
-    complete_time: SystemTime,
+    /// The press this task serves, and the version and release of the order in the order book it serves.
+    id: elev_controller::OrderId,
+    version: u32,
+    releases: u32,
+    /// When the button was pressed, on the clock of the elevator it was pressed in.
+    created_ms: u64,
     task_delay: CostFunctionDelay,
     origin_id: u32,
+    /// When this elevator first learnt of the order.
+    created: SystemTime,
+    state_since: SystemTime,
 }
 
-#[derive(PartialEq, Clone, Debug)]
-enum States {
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum States {
     New,
     DelayTake,
     Take,
@@ -36,258 +58,600 @@ struct CostFunctionDelay {
     waiting_time: Duration,
 }
 
-#[derive(PartialEq, Debug)]
-enum Direction {
-    Up,
-    Down
-}
-
-pub struct TaskManager {
-    elevator: elev_controller::ElevController,
+pub struct TaskManager<H: ElevHardware> {
+    elevator: elev_controller::ElevController<H>,
     task_list: Vec<Task>,
-    elevator_id: u32
+    elevator_id: u32,
+    clock: SharedClock,
+    journal: Option<Journal>,
+    peers: PeerTable,
+    next_heartbeat: SystemTime,
+    last_heartbeat: Option<Heartbeat>,
+    assignment_mode: AssignmentMode,
+    cost_function: Box<dyn CostFunction>,
+    order_timing: OrderConfig,
+    building: Building,
+    events: EventRecorder,
+    metrics: SharedMetrics,
+    pending_acks: PendingAcks,
+    ack_timeout: Duration,
+    book: OrderBook,
+    next_digest: SystemTime,
+    digest_interval: Duration,
 }
 
 impl Task {
-    pub fn new(order: elev_controller::Order, origin_id: u32) -> io::Result<Self> {
-        let default_delay = CostFunctionDelay {current_time: SystemTime::now(), waiting_time: Duration::from_secs(1)};
-        let task = Task {order: order, state: States::New, taken: false, complete: false, complete_time: SystemTime::now(), task_delay: default_delay, origin_id: origin_id};
-        Ok(task)
+    pub fn new(order: elev_controller::Order, origin_id: u32, id: elev_controller::OrderId, replica: &OrderState, now: SystemTime) -> Self {
+        let default_delay = CostFunctionDelay {current_time: now, waiting_time: Duration::from_secs(1)};
+        Task {order, state: States::New, id, version: replica.version, releases: replica.releases, created_ms: replica.created_ms, task_delay: default_delay, origin_id,
+              created: now, state_since: now}
+    }
+
+    /// Whether the order has been taken according to its state in the order book.
+    /// A newer version of the order means the version of this task is over.
+    fn is_taken(&self, state: &OrderState) -> bool {
+        state.version > self.version || state.is_taken()
+    }
+
+    fn is_served(&self, state: &OrderState) -> bool {
+        state.version > self.version || state.is_served()
+    }
+
+    /// Moves the task to another state, every transition is logged so one order can be followed through the log.
+    /// The time spent in the old state and the whole lifetime of completed orders go to `metrics`.
+    fn set_state(&mut self, state: States, now: SystemTime, events: &mut EventRecorder, metrics: &Metrics) {
+        info!(elevator = events.elevator_id(), id:% = self.id, floor = self.order.floor, order_type:? = self.order.order_type, origin = self.origin_id, from:? = self.state, to:? = state; "Order state changed");
+        events.record(EventKind::Transition{order: self.order.clone(), origin: self.origin_id, id: self.id, from: self.state.clone(), to: state.clone()});
+        let time_in_state = now.duration_since(self.state_since).unwrap_or_default();
+        match (&self.state, &state) {
+            (States::DelayTake, _) => metrics.delay_take_ended(time_in_state),
+            (States::CompleteWatchdog, States::Take) => metrics.watchdog_takeover(),
+            _ => {}
+        }
+        if state == States::Complete {
+            metrics.order_completed(now.duration_since(self.created).unwrap_or_default());
+        }
+        self.state = state;
+        self.state_since = now;
     }
 }
 
-impl TaskManager {
-    pub fn new(internal_sender: Sender<elev_controller::ButtonEvent>, elevator_id: u32, udp_broadcast_port: u16, elevator_ip: &str, elevator_port: u16) -> io::Result<Self> {
-        let elev_controller = elev_controller::ElevController::new(internal_sender, elevator_id, udp_broadcast_port, elevator_ip, elevator_port).unwrap();
+impl<H: ElevHardware> TaskManager<H> {
+    pub fn new(elevator_id: u32, transmitter: Box<dyn MessageTransmitter>, elev_driver: H, clock: SharedClock, journal: Option<Journal>) -> ElevResult<Self> {
+        let elev_controller = elev_controller::ElevController::new(elevator_id, transmitter, elev_driver, clock.clone())?;
         let task_vec = Vec::new();
-        let manager = TaskManager {elevator: elev_controller, task_list: task_vec, elevator_id: elevator_id};
+        let now = clock.now();
+        let events = EventRecorder::new(elevator_id, clock.clone());
+        let mut manager = TaskManager {elevator: elev_controller, task_list: task_vec, elevator_id, clock, journal, peers: PeerTable::new(elevator_id), next_heartbeat: now, last_heartbeat: None, assignment_mode: AssignmentMode::TimerRace, cost_function: CostConfig::default().function.build(&CostConfig::default()),
+                                  order_timing: OrderConfig::default(), building: Building::default(),
+                                  events, metrics: Metrics::shared(),
+                                  pending_acks: PendingAcks::new(), ack_timeout: NetworkConfig::default().ack_timeout(),
+                                  book: OrderBook::new(), next_digest: now, digest_interval: NetworkConfig::default().digest_interval()};
+        manager.replay_journal()?;
         Ok(manager)
     }
 
-    pub fn add_new_task(&mut self, order: elev_controller::Order, origin_id: u32) {
-        let new_task = Task::new(order, origin_id).unwrap();
-        let mut task_exist = false;
-        for task in &mut self.task_list {
-            if task.order == new_task.order {
-                task_exist = true;
-                if new_task.order.order_type == elev_controller::ButtonType::CabCall && task.origin_id != new_task.origin_id {
-                    task_exist = false;
+    pub fn set_assignment_mode(&mut self, mode: AssignmentMode) {
+        self.assignment_mode = mode;
+    }
+
+    pub fn set_service_mode(&mut self, mode: elev_controller::ServiceMode) {
+        self.elevator.set_service_mode(mode);
+    }
+
+    /// Applies the timing and cost function settings from the configuration file.
+    pub fn set_config(&mut self, config: &Config) {
+        self.elevator.set_timing(config.elevator.clone());
+        self.order_timing = config.orders.clone();
+        self.ack_timeout = config.network.ack_timeout();
+        self.digest_interval = config.network.digest_interval();
+        self.cost_function = config.cost.function.build(&config.cost);
+        self.set_building(config.building.clone());
+    }
+
+    pub fn set_building(&mut self, building: Building) {
+        self.elevator.set_building(building.clone());
+        self.peers.set_building(building.clone());
+        self.building = building;
+    }
+
+    /// Records button events and order state changes in `sink` from now on.
+    pub fn set_event_sink(&mut self, sink: Box<dyn EventSink>) {
+        self.events.set_sink(sink);
+    }
+
+    /// Counts dispatch metrics in `metrics` from now on.
+    pub fn set_metrics(&mut self, metrics: SharedMetrics) {
+        self.elevator.set_metrics(metrics.clone());
+        self.metrics = metrics;
+    }
+
+    /// Requests the orders left in the journal by a previous run again, so they are served
+    /// even if no peer is alive to repeat them.
+    fn replay_journal(&mut self) -> ElevResult<()> {
+        let entry = match self.journal.as_mut() {
+            Some(journal) => match journal.load() {
+                Ok(entry) => entry,
+                Err(e) => {
+                    error!("Unable to read journal: {}", e);
+                    return Ok(());
                 }
+            },
+            None => return Ok(()),
+        };
+        if entry.cab_calls.is_empty() && entry.taken_hall_calls.is_empty() {
+            return Ok(());
+        }
+        info!("Restoring {} cab calls and {} hall calls from journal", entry.cab_calls.len(), entry.taken_hall_calls.len());
+        for order in entry.cab_calls {
+            self.elevator.request_order(order);
+        }
+        // Taken again for the same press, so peers serving it meanwhile see the same order
+        for taken in entry.taken_hall_calls {
+            self.elevator.set_button_light_for_order(&taken.order.order_type, elev_driver::Floor::At(taken.order.floor), elev_driver::Light::On)?;
+            self.elevator.add_order(taken);
+        }
+        Ok(())
+    }
+
+    /// Writes the pending cab calls and taken hall calls to the journal if they have changed.
+    fn update_journal(&mut self) {
+        let journal = match self.journal.as_mut() {
+            Some(journal) => journal,
+            None => return,
+        };
+        let mut entry = JournalEntry::default();
+        for task in &self.task_list {
+            if task.order.order_type == elev_controller::ButtonType::CabCall && task.origin_id == self.elevator_id && !task.is_served(&self.book.state(&task.order, task.origin_id)) {
+                entry.cab_calls.push(task.order.clone());
+            }
+        }
+        for taken in self.elevator.taken_orders() {
+            if taken.order.order_type != elev_controller::ButtonType::CabCall {
+                entry.taken_hall_calls.push(taken.clone());
             }
         }
-        if !task_exist {
-            self.task_list.push(new_task);
+        if let Err(e) = journal.record(entry) {
+            error!("Unable to write journal: {}", e);
         }
     }
 
-    pub fn set_task_taken(&mut self, order: elev_controller::Order, origin_id: u32) {
-        for task in &mut self.task_list {
-            if task.order == order && order.order_type != elev_controller::ButtonType::CabCall {
-                task.taken = true;
-            } else if task.order == order && order.order_type == elev_controller::ButtonType::CabCall && task.origin_id == origin_id {
-                task.taken = true;
+    /// Handles an envelope received from the network. Echoes of the broadcasts of this elevator
+    /// and copies of envelopes already handled are dropped, its own events are handled by `deliver_local_events`.
+    pub fn handle_envelope(&mut self, envelope: Envelope) {
+        match self.elevator.receive(&envelope) {
+            Ok(Some(message)) => self.handle_message(message),
+            Ok(None) => {}
+            Err(e) => warn!("Rejected packet from elevator {}: {}", envelope.sender_id, e),
+        }
+    }
+
+    pub fn handle_message(&mut self, message: Message) {
+        match message {
+            Message::Button(event) => {
+                self.events.record(EventKind::Received(event.clone()));
+                self.acknowledge(&event);
+                self.handle_button_event(event);
+            }
+            Message::Heartbeat(heartbeat) => self.handle_heartbeat(heartbeat),
+            Message::Ack(ack) => {
+                if ack.event.origin == self.elevator_id {
+                    self.pending_acks.acknowledged(&ack.event, ack.acked_by);
+                }
+            }
+            Message::Digest(digest) => self.reconcile(digest),
+        }
+    }
+
+    pub fn handle_heartbeat(&mut self, heartbeat: Heartbeat) {
+        match self.peers.heartbeat_received(heartbeat, self.clock.now()) {
+            Some(PeerEvent::Joined(id)) => {
+                if let Some(peer) = self.peers.get(id) {
+                    info!("Elevator {} joined at floor {}", id, peer.heartbeat.floor);
+                }
+            }
+            Some(PeerEvent::OutOfService(id)) => {
+                warn!("Elevator {} is out of service", id);
+                self.release_tasks_held_by(id);
+            }
+            Some(PeerEvent::Refused(id)) => {
+                error!("Refusing elevator {}, it was started with another building than {:?}", id, self.building);
+                self.release_tasks_held_by(id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Acknowledges `Taken` and `Complete` events of other elevators, every copy received is
+    /// acknowledged since the acknowledgement of an earlier one may have been lost.
+    fn acknowledge(&mut self, event: &elev_controller::ButtonEvent) {
+        if event.origin == self.elevator_id || !delivery::needs_acknowledgement(event) || self.peers.is_refused(event.origin) {
+            return;
+        }
+        let ack = Ack{event: event.clone(), acked_by: self.elevator_id};
+        if let Err(e) = self.elevator.transmit(&Message::Ack(ack)) {
+            warn!("Unable to acknowledge {:?}: {}", event, e);
+        }
+    }
+
+    /// Sends the events of this elevator which have not been acknowledged in time again,
+    /// until every live peer has acknowledged them.
+    fn retransmit_unacknowledged(&mut self) -> ElevResult<()> {
+        let live: BTreeSet<u32> = self.peers.live_peers().map(|peer| peer.heartbeat.elevator_id).collect();
+        for pending in self.pending_acks.due(self.clock.now(), self.ack_timeout, &live) {
+            let event = pending.event;
+            debug!(request:? = event.request, id:% = event.id, floor = event.order.floor, order_type:? = event.order.order_type, awaiting:? = pending.awaiting, retransmissions = pending.retransmissions;
+                   "Sending unacknowledged event again");
+            self.metrics.ack_retransmission();
+            self.events.record(EventKind::Sent(event.clone()));
+            self.elevator.transmit(&Message::Button(event))?;
+        }
+        self.metrics.set_unacknowledged(self.pending_acks.unacknowledged().len());
+        Ok(())
+    }
+
+    /// Announces the state of this elevator if the heartbeat interval has passed.
+    fn send_heartbeat(&mut self) -> ElevResult<()> {
+        let now = self.clock.now();
+        if now < self.next_heartbeat {
+            return Ok(());
+        }
+        self.next_heartbeat = now + HEARTBEAT_INTERVAL;
+        let heartbeat = Heartbeat {
+            elevator_id: self.elevator_id,
+            floor: self.elevator.get_previous_floor(),
+            direction: self.elevator.get_direction(),
+            door_open: self.elevator.is_door_open(),
+            queue_length: self.elevator.get_order_list().len(),
+            out_of_service: self.elevator.is_out_of_service(),
+            building: self.building.clone(),
+        };
+        self.last_heartbeat = Some(heartbeat.clone());
+        self.elevator.transmit(&Message::Heartbeat(heartbeat))
+    }
+
+    /// Broadcasts the order book if the digest interval has passed.
+    fn send_digest(&mut self) -> ElevResult<()> {
+        let now = self.clock.now();
+        if now < self.next_digest {
+            return Ok(());
+        }
+        self.next_digest = now + self.digest_interval;
+        let digest = Digest{elevator_id: self.elevator_id, orders: self.book.orders().to_vec()};
+        self.elevator.transmit(&Message::Digest(digest))
+    }
+
+    /// Merges the order book of a peer into this one. Orders only the peer knew to be waiting
+    /// get a task, which lights their lamps, and orders the peer knows to be served are completed,
+    /// which turns them off.
+    fn reconcile(&mut self, digest: Digest) {
+        let peer = digest.elevator_id;
+        if peer == self.elevator_id || self.peers.is_refused(peer) {
+            return;
+        }
+        for replicated in digest.orders {
+            if self.peers.is_refused(replicated.origin) {
+                continue;
+            }
+            let before = self.book.state(&replicated.order, replicated.origin);
+            if !self.book.merge(&replicated.order, replicated.origin, &replicated.state) {
+                continue;
+            }
+            debug!(peer = peer, from:? = before, to:? = replicated.state; "Merged the state of {:?} of elevator {}", replicated.order, replicated.origin);
+            self.metrics.digest_repair(&format!("{:?}", replicated.state.phase).to_lowercase());
+            if before.is_active() && !replicated.state.is_active() {
+                info!("Elevator {} has seen {:?} of elevator {} served, dropping it", peer, replicated.order, replicated.origin);
             }
+            self.track_order(&replicated.order, replicated.origin);
         }
     }
 
-    pub fn set_task_complete(&mut self, order: elev_controller::Order, origin_id: u32) {
+    /// Gives an order the order book says is waiting to be served a task, unless it has one.
+    /// A task of an earlier press of the button is left to complete.
+    fn track_order(&mut self, order: &elev_controller::Order, origin_id: u32) {
+        let state = self.book.state(order, origin_id);
+        let id = match state.id {
+            Some(id) if state.is_active() => id,
+            _ => return,
+        };
+        let now = self.clock.now();
+        let existing = self.task_list.iter_mut().find(|task| order_state::same_order(&task.order, task.origin_id, order, origin_id) && task.state != States::Complete &&
+                                                       (task.id == id || task.version == state.version));
+        if let Some(task) = existing {
+            // The same order, known by the id of a press made at the same time or released by the elevator it was assigned to
+            if (task.version, task.releases) < (state.version, state.releases) && task.state == States::CompleteWatchdog && !state.is_taken() {
+                task.set_state(States::New, now, &mut self.events, &self.metrics);
+            }
+            task.id = id;
+            task.version = state.version;
+            task.releases = state.releases;
+            return;
+        }
+        if !self.building.has_button_for(order) {
+            warn!("Ignoring {:?} from elevator {}, there is no such button in the building", order, origin_id);
+            return;
+        }
+        self.metrics.order_requested(&order.order_type);
+        let mut task = Task::new(order.clone(), origin_id, id, &state, now);
+        if state.assignee == Some(self.elevator_id) && self.elevator.get_order_list().contains(order) {
+            // Already in the queue of the car, like a hall call restored from the journal
+            task.set_state(States::CheckLocalComplete, now, &mut self.events, &self.metrics);
+        }
+        self.task_list.push(task);
+    }
+
+    /// Elevator which should take a hall call according to the shared heartbeat state.
+    /// This elevator is judged from its last heartbeat, which is what the peers see.
+    fn shared_assignment(peers: &PeerTable, own_heartbeat: &Option<Heartbeat>, order: &elev_controller::Order, floors: u8) -> Option<u32> {
+        let mut candidates: Vec<&Heartbeat> = peers.serving_peers().map(|peer| &peer.heartbeat).collect();
+        candidates.extend(own_heartbeat.iter().filter(|heartbeat| !heartbeat.out_of_service));
+        assignment::assign(order, &candidates, floors)
+    }
+
+    fn handle_peer_events(&mut self) {
+        for event in self.peers.update(self.clock.now()) {
+            if let PeerEvent::Left(id) = event {
+                warn!("Elevator {} left", id);
+                self.release_tasks_held_by(id);
+            }
+        }
+    }
+
+    /// Puts hall calls taken by a peer that has died or gone out of service back up for grabs
+    /// at once, instead of waiting for the complete watchdog to expire.
+    fn release_tasks_held_by(&mut self, id: u32) {
+        let now = self.clock.now();
         for task in &mut self.task_list {
-            if task.order == order && order.order_type != elev_controller::ButtonType::CabCall {
-                task.complete_time = SystemTime::now();
-                task.complete = true;
-            } else if task.order == order && order.order_type == elev_controller::ButtonType::CabCall && task.origin_id == origin_id {
-                task.complete_time = SystemTime::now();
-                task.complete = true;
+            let state = self.book.state(&task.order, task.origin_id);
+            if task.state == States::CompleteWatchdog && state.assignee == Some(id) && !task.is_served(&state) {
+                info!("Reassigning {:?} held by elevator {}", task.order, id);
+                self.book.update(&task.order, task.origin_id, |state| state.released());
+                task.releases = self.book.state(&task.order, task.origin_id).releases;
+                task.set_state(States::New, now, &mut self.events, &self.metrics);
+            }
+        }
+    }
+
+    /// Gives up the hall calls in the queue while this elevator is out of service,
+    /// as long as some peer is able to serve them. They are taken back if nobody else does
+    /// once the elevator is in service again.
+    fn hand_over_hall_calls(&mut self) {
+        if !self.elevator.is_out_of_service() || self.peers.serving_peers().next().is_none() {
+            return;
+        }
+        let now = self.clock.now();
+        for order in self.elevator.get_order_list() {
+            if order.order_type == elev_controller::ButtonType::CabCall {
+                continue;
+            }
+            info!("Handing {:?} over to the other elevators", order);
+            self.elevator.delete_order(&order);
+            for task in &mut self.task_list {
+                if task.order == order && !task.is_served(&self.book.state(&task.order, task.origin_id)) {
+                    self.book.update(&task.order, task.origin_id, |state| state.released());
+                    task.releases = self.book.state(&task.order, task.origin_id).releases;
+                    task.set_state(States::DelayTake, now, &mut self.events, &self.metrics);
+                    task.task_delay.current_time = now;
+                }
+            }
+        }
+    }
+
+    /// Handles the button events broadcast by this elevator since the last call, each of them once.
+    pub fn deliver_local_events(&mut self) {
+        while let Some(event) = self.elevator.next_local_event() {
+            self.handle_local_event(event);
+        }
+    }
+
+    /// Handles a button event broadcast by this elevator.
+    fn handle_local_event(&mut self, msg: elev_controller::ButtonEvent) {
+        self.events.record(EventKind::Sent(msg.clone()));
+        if delivery::needs_acknowledgement(&msg) {
+            let peers = self.peers.live_peers().map(|peer| peer.heartbeat.elevator_id);
+            self.pending_acks.track(msg.clone(), peers, self.clock.now());
+        }
+        self.handle_button_event(msg);
+    }
+
+    /// Applies a button event to the order book. Events about presses already served are
+    /// ignored, see `OrderBook::classify`.
+    pub fn handle_button_event(&mut self, msg: elev_controller::ButtonEvent) {
+        if self.peers.is_refused(msg.origin) {
+            return;
+        }
+        if !self.building.has_button_for(&msg.order) {
+            warn!("Ignoring {:?} from elevator {}, there is no such button in the building", msg.order, msg.origin);
+            return;
+        }
+        match self.book.classify(&msg.order, msg.origin, &msg.id) {
+            Press::New => self.book.request(&msg.order, msg.origin, msg.id, msg.created_ms),
+            Press::Current => {}
+            Press::Stale => {
+                debug!(request:? = msg.request, id:% = msg.id, origin = msg.origin; "Ignoring stale event for {:?}", msg.order);
+                return;
             }
         }
+        // Only the version and release the event was made for change, so a late `Taken`
+        // of an elevator whose order has been released since does not assign it back
+        if msg.request != elev_controller::RequestType::Request {
+            self.book.merge(&msg.order, msg.origin, &OrderState::reported_by(&msg));
+        }
+        self.track_order(&msg.order, msg.origin);
     }
 
-    pub fn run_state_machine(&mut self) {
-        self.elevator.handle_order();
-        self.elevator.broadcast_active_buttons();
+    pub fn run_state_machine(&mut self) -> ElevResult<()> {
+        if !self.elevator.is_connected() {
+            // Orders keep being tracked from the network while the hardware is gone,
+            // peers take over the hall calls through their watchdogs
+            if !self.elevator.try_reconnect() {
+                return Ok(());
+            }
+            self.restore_lights()?;
+        }
+        self.handle_peer_events();
+        self.elevator.handle_order()?;
+        self.hand_over_hall_calls();
+        self.elevator.broadcast_active_buttons()?;
+        let out_of_service = self.elevator.is_out_of_service();
         let mut task_delete_cleanup: std::vec::Vec<Task> = vec![];
-        let tasks_copy = self.task_list.to_vec();
+        let order_list = self.elevator.get_order_list();
+        self.metrics.set_queue_length(order_list.len());
+        let now = self.clock.now();
+        let car = CarState{queue: &order_list, current_floor: self.elevator.get_current_floor()?, last_floor: self.elevator.get_previous_floor(),
+                           direction: self.elevator.get_direction(), elevator_id: self.elevator_id, floors: self.building.floors};
         for task in &mut self.task_list {
+            let replica = self.book.state(&task.order, task.origin_id);
             match task.state {
                 States::New => {
                     if task.origin_id != self.elevator_id && task.order.order_type == elev_controller::ButtonType::CabCall {
-                        task.state = States::CabWatchdog;
-                        task.task_delay.current_time = SystemTime::now();
+                        task.set_state(States::CabWatchdog, now, &mut self.events, &self.metrics);
+                        task.task_delay.current_time = self.clock.now();
                     } else {
-                        task.state = States::DelayTake;
-                        task.task_delay.current_time = SystemTime::now();
-                        task.task_delay.waiting_time = TaskManager::cost_function_delay_take(&task, &tasks_copy, &self.elevator.get_order_list(), self.elevator.get_current_floor(), self.elevator.get_previous_floor(), self.elevator_id);
-                        self.elevator.set_button_light_for_order(&task.order.order_type, elev_driver::Floor::At(task.order.floor), elev_driver::Light::On);
+                        task.set_state(States::DelayTake, now, &mut self.events, &self.metrics);
+                        task.task_delay.current_time = self.clock.now();
+                        task.task_delay.waiting_time = self.cost_function.cost(&task.order, &car);
+                        self.elevator.set_button_light_for_order(&task.order.order_type, elev_driver::Floor::At(task.order.floor), elev_driver::Light::On)?;
+                        if self.assignment_mode == AssignmentMode::Shared && task.order.order_type != elev_controller::ButtonType::CabCall && !task.is_taken(&replica) {
+                            // The delay above stays as the fallback if the assigned elevator never takes the order
+                            if Self::shared_assignment(&self.peers, &self.last_heartbeat, &task.order, self.building.floors) == Some(self.elevator_id) {
+                                task.set_state(States::Take, now, &mut self.events, &self.metrics);
+                            }
+                        }
                     }
                 }
                 States::DelayTake => {
-                    if task.taken {
-                        task.state = States::CompleteWatchdog;
-                        task.task_delay.current_time = SystemTime::now();
-                        task.task_delay.waiting_time = TaskManager::cost_function_delay_complete(&task, &tasks_copy, &self.elevator.get_order_list(), self.elevator.get_current_floor(), self.elevator.get_previous_floor(), self.elevator_id); 
-                    } else if task.task_delay.current_time.elapsed().unwrap() > task.task_delay.waiting_time {
-                        task.state = States::Take;
+                    if task.is_taken(&replica) {
+                        task.set_state(States::CompleteWatchdog, now, &mut self.events, &self.metrics);
+                        task.task_delay.current_time = self.clock.now();
+                        task.task_delay.waiting_time = self.order_timing.complete_watchdog(self.building.floors) + self.cost_function.cost(&task.order, &car);
+                    } else if self.clock.elapsed(&mut task.task_delay.current_time)? > task.task_delay.waiting_time && !out_of_service {
+                        task.set_state(States::Take, now, &mut self.events, &self.metrics);
                     }
                 }
                 // Monitors if any hallcalls orders have timed out after a elevator has taken it
                 States::CompleteWatchdog => {
-                    if task.complete {
-                        task.state = States::Complete;
-                    } else if task.task_delay.current_time.elapsed().unwrap() > task.task_delay.waiting_time {
-                        task.state = States::Take;
+                    if task.is_served(&replica) {
+                        task.set_state(States::Complete, now, &mut self.events, &self.metrics);
+                    } else if self.clock.elapsed(&mut task.task_delay.current_time)? > task.task_delay.waiting_time && !out_of_service {
+                        task.set_state(States::Take, now, &mut self.events, &self.metrics);
                     }
                 }
                 // Monitors other elevators cabcalls and broadcasts them until they are complete
                 States::CabWatchdog => {
-                    if task.complete {
-                        task.state = States::Complete;
+                    if task.is_served(&replica) {
+                        task.set_state(States::Complete, now, &mut self.events, &self.metrics);
                     } else {
-                        if task.task_delay.current_time.elapsed().unwrap() > Duration::from_secs(10) {
-                            println!("[task_manager]: Repeating CabCall Order {:?} {:?}", task.order, task.origin_id);
-                            task.task_delay.current_time = SystemTime::now();
-                            let order_clone = task.order.clone();
-                            self.elevator.broadcast_order(order_clone, elev_controller::RequestType::Request, task.origin_id);
+                        if self.clock.elapsed(&mut task.task_delay.current_time)? > self.order_timing.cab_rebroadcast_interval() {
+                            self.metrics.cab_rebroadcast();
+                            debug!("Repeating cab call {:?} of elevator {}", task.order, task.origin_id);
+                            task.task_delay.current_time = self.clock.now();
+                            let request = elev_controller::ButtonEvent{request: elev_controller::RequestType::Request, order: task.order.clone(), origin: task.origin_id,
+                                                                        id: task.id, version: 0, releases: 0, created_ms: task.created_ms};
+                            self.elevator.broadcast_order(request);
                         }
                     }
                 }
                 States::Take => {
-                    let order_clone = task.order.clone();
-                    self.elevator.add_order(order_clone);
-                    task.state = States::CheckLocalComplete;
+                    let taken = elev_controller::ButtonEvent{request: elev_controller::RequestType::Taken, order: task.order.clone(), origin: self.elevator_id,
+                                                             id: task.id, version: task.version, releases: task.releases, created_ms: task.created_ms};
+                    task.set_state(States::CheckLocalComplete, now, &mut self.events, &self.metrics);
+                    self.elevator.add_order(taken);
                 }
                 States::CheckLocalComplete => {
-                    if task.complete {
-                        task.state = States::Complete;
+                    if task.is_served(&replica) {
+                        task.set_state(States::Complete, now, &mut self.events, &self.metrics);
                         if task.order.order_type != elev_controller::ButtonType::CabCall {
                             self.elevator.delete_order(&task.order);
                         } 
                     }
                 }
                 States::Complete => {
-                    if (task.order.order_type == elev_controller::ButtonType::CabCall && task.origin_id == self.elevator_id) || 
-                            task.order.order_type != elev_controller::ButtonType::CabCall {
-                        self.elevator.set_button_light_for_order(&task.order.order_type, elev_driver::Floor::At(task.order.floor), elev_driver::Light::Off);
+                    // A newer press of the button keeps the lamp lit
+                    if ((task.order.order_type == elev_controller::ButtonType::CabCall && task.origin_id == self.elevator_id) || 
+                            task.order.order_type != elev_controller::ButtonType::CabCall) && !replica.is_active() {
+                        self.elevator.set_button_light_for_order(&task.order.order_type, elev_driver::Floor::At(task.order.floor), elev_driver::Light::Off)?;
                     }
                     task_delete_cleanup.push(task.clone());
                 }
             }
         }
         for task in task_delete_cleanup {
-            if task.complete_time.elapsed().unwrap() > Duration::from_secs(5) || task.order.order_type != elev_controller::ButtonType::CabCall {
-                let index = self.task_list.iter().position(|x| *x == task).unwrap();
+            if let Some(index) = self.task_list.iter().position(|x| *x == task) {
                 self.task_list.remove(index);
-            }  
+            }
         }
+        self.update_journal();
+        // Sent last, so a network failure holds up none of the work above
+        self.send_heartbeat().and(self.send_digest()).and(self.retransmit_unacknowledged())
     }
 
-    fn cost_function_delay_take(task_order: &Task, task_queue: &Vec<Task>, elev_queue: &VecDeque<elev_controller::Order>, current_floor: isize, last_floor: isize, elev_id: u32) -> Duration {
-        // Number of floors, Distance between elevator and call, Direction of elevator
-
-        
-        let ip_score=elev_id;
-        let direction = TaskManager::direction_of_call(current_floor, last_floor);
-        let incoming_order = &task_order;
-
-        
-
-        let mut number_of_elevator_orders = 0;
-        for _elev_orders in elev_queue {
-            number_of_elevator_orders += 1;
-        }
-        // Override variables used to manipulate cost function delay
-        let mut score_override=1;
-        let mut ip_score_override=1;
-        let mut long_queue_delay_override=1;
-
-        match elev_queue.front() {
-            Some(elev_current_doing) => {
-                // There are other orders in the elevator
+    /// Stops the car before shutting down.
+    pub fn safe_stop(&self) {
+        self.elevator.safe_stop();
+    }
 
-                let score; // Higher is better, must be > 0
-                // Based upon Dong & Zafar's Elevator Scheduling (http://www.columbia.edu/~cs2035/courses/ieor4405.S13/p14.pdf)
-                match elev_current_doing.order_type {
-                    elev_controller::ButtonType::CabCall => {
-                        if direction == Direction::Down && last_floor > task_order.order.floor as isize ||
-                        direction == Direction::Up && last_floor < task_order.order.floor as isize{
-                            // Elevator moving towards order
-                            score = (elev_driver::N_FLOORS as isize + 2) - (task_order.order.floor as isize - last_floor).abs();
-                        }
-                        else{
-                            // Elevator moving away from order
-                            score = 1;
-                        }
+    /// Stops driving the car after a hardware error, it is reconnected from `run_state_machine`.
+    pub fn hardware_lost(&mut self) {
+        self.elevator.hardware_lost();
+    }
 
-                    }
-                    elev_controller::ButtonType::HallDownCall => {
-                        if direction == Direction::Down && last_floor > task_order.order.floor as isize {
-                            // Elevator moving to order /w same direction
-                            score = (elev_driver::N_FLOORS as isize + 2) - (task_order.order.floor as isize - last_floor).abs();
-                        } else if direction == Direction::Up && last_floor < task_order.order.floor as isize {
-                            // Elevator moving to order /w opposit direction
-                            score = (elev_driver::N_FLOORS as isize + 1) - (task_order.order.floor as isize - last_floor).abs();
-                        } else {
-                            // Away from order
-                            score = 1;
-                        }
-                    }
-                    elev_controller::ButtonType::HallUpCall => {
-                        if direction == Direction::Up && last_floor < task_order.order.floor as isize {
-                            // Elevator moving to order /w same direction
-                            score = (elev_driver::N_FLOORS as isize + 2) - (task_order.order.floor as isize - last_floor).abs();
-                        } else if direction == Direction::Down && last_floor > task_order.order.floor as isize {
-                            // Elevator moving to order /w opposit direction
-                            score = (elev_driver::N_FLOORS as isize + 1) - (task_order.order.floor as isize - last_floor).abs();
-                        } else {
-                            // Away from order
-                            score = 1;
-                        }
+    /// Lights the buttons of every order still active, used after the hardware has been reconnected.
+    fn restore_lights(&mut self) -> ElevResult<()> {
+        for task in &self.task_list {
+            match task.state {
+                States::New | States::CabWatchdog | States::Complete => {}
+                _ => {
+                    if task.order.order_type != elev_controller::ButtonType::CabCall || task.origin_id == self.elevator_id {
+                        self.elevator.set_button_light_for_order(&task.order.order_type, elev_driver::Floor::At(task.order.floor), elev_driver::Light::On)?;
                     }
                 }
-                if incoming_order.order.order_type==elev_controller::ButtonType::CabCall || incoming_order.order.floor==elev_current_doing.floor{
-                    ip_score_override=0;
-                    long_queue_delay_override=0;
-                    score_override=0;
-                } 
-                let delay =2000+(5000/score)* score_override+2500 * number_of_elevator_orders * long_queue_delay_override+150 * ip_score as isize * ip_score_override;
-                // basis_delay+score_delay    +        amount_of_order_delay      +                         unique_ip_delay
-                println!("[COST_DEBUG]: score_some_queue {:?} elev_orders {:?}", score, number_of_elevator_orders);
-                println!("[COST_DEBUG]: delay {:?}", delay);
-
-                Duration::from_millis(delay as u64)
-            }
-            None => {
-
-                   //There is no other orders in the elevator
-                    let mut delay =1000;
-
-                    let current_order =&task_order.order;
-                    if current_order.order_type==elev_controller::ButtonType::CabCall{
-                        delay=20;
-                    }
-                    else{
-                        let distance_score =(current_floor-current_order.floor as isize).abs();
-                        delay=delay+500*distance_score as u64 +150*ip_score as u64;
-    
-                    }
-                    println!("[COST_DEBUG]: DELAY : {:?}",delay);
-                    println!("[COST_DEBUG]: TASK Q: {:?}",task_queue[0].order.order_type);
-                    println!("[COST_DEBUG]: Elev Q: {:?}",elev_queue.front());
-                    Duration::from_millis(delay)
             }
         }
+        Ok(())
     }
 
-    fn direction_of_call(going_to: isize, last_floor: isize) -> Direction { 
-        let dir;
-        if going_to - last_floor > 0 {
-            dir = Direction::Up;
-        } else {
-            dir = Direction::Down;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Arc;
+    use elevator_driver::elev_driver::*;
+    use crate::clock::VirtualClock;
+    use crate::elev_simulator::{SimElevator, DEFAULT_TRAVEL_TIME};
+    use crate::error::RecoveryPolicy;
+
+    /// A network which is down, every send fails.
+    struct FailingTransmitter;
+
+    impl MessageTransmitter for FailingTransmitter {
+        fn transmit(&self, _message: &Message) -> io::Result<u64> {
+            Err(io::Error::from(io::ErrorKind::NotConnected))
         }
-        dir
     }
 
-    fn cost_function_delay_complete(task_order: &Task, task_queue: &Vec<Task>, elev_queue: &VecDeque<elev_controller::Order>, current_floor: isize, last_floor: isize, elev_id: u32) -> Duration {
-        Duration::from_secs(elev_driver::N_FLOORS as u64 * 3) + TaskManager::cost_function_delay_take(task_order, task_queue, elev_queue, current_floor, last_floor, elev_id)
+    #[test]
+    fn serves_own_cab_calls_without_a_network() {
+        let clock = VirtualClock::new(SystemTime::UNIX_EPOCH);
+        let shared_clock: SharedClock = Arc::new(clock.clone());
+        let car = SimElevator::new(N_FLOORS, DEFAULT_TRAVEL_TIME, shared_clock.clone());
+        let mut manager = TaskManager::new(1, Box::new(FailingTransmitter), car.clone(), shared_clock, None).unwrap();
+        let floors = [2, 3];
+        for floor in floors.iter() {
+            car.press_button(Button::Internal(Floor::At(*floor))).unwrap();
+        }
+        for _ in 0..3000 {
+            manager.deliver_local_events();
+            if let Err(e) = manager.run_state_machine() {
+                assert_eq!(e.policy(), RecoveryPolicy::SkipTick, "{}", e);
+            }
+            clock.advance(Duration::from_millis(10));
+        }
+        assert!(manager.task_list.is_empty(), "{:?}", manager.task_list);
+        assert!(manager.elevator.get_order_list().is_empty());
+        for floor in floors.iter() {
+            assert!(!car.is_button_lit(Button::Internal(Floor::At(*floor))), "cab call to floor {} is still lit", floor);
+        }
     }
-}
\ No newline at end of file
+}